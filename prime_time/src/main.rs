@@ -1,91 +1,362 @@
-use anyhow::Result;
-use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+use std::sync::Arc;
 
-fn is_prime(n: f64) -> bool {
-    let n = n as u64;
-    if n <= 1 {
-        return false;
-    }
+use anyhow::Result;
+use futures::stream::{FuturesOrdered, StreamExt};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::sync::Semaphore;
+use tokio::task::spawn_blocking;
 
-    if n <= 3 {
-        return true;
-    }
+use methods::Registry;
+use request::Malformed;
 
-    if n % 2 == 0 || n % 3 == 0 {
-        return false;
-    }
+mod methods;
+mod number;
+mod prime;
+mod request;
 
-    let mut i = 5;
-    while i * i <= n {
-        if n % i == 0 || n % (i + 2) == 0 {
-            return false;
-        }
-        i += 6;
-    }
+// Requests of one connection read ahead of the oldest unanswered one. Past
+// that, no more lines are read until it is answered.
+const MAX_IN_FLIGHT: usize = 16;
 
-    true
+/// Permits to run requests on the blocking pool.
+#[derive(Debug, Clone)]
+struct Jobs {
+    // Shared by every connection
+    pool: Arc<Semaphore>,
+    // Permits of the pool one connection may hold at once, fewer than the
+    // pool has unless it only has one
+    per_connection: usize,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Request {
-    number: f64,
-    method: String,
+/// What to do after answering a malformed request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    /// Close the connection, as the protocol asks.
+    Strict,
+    /// Go on with the requests after it.
+    Lenient,
 }
 
-#[derive(Serialize, Deserialize)]
-struct Response {
-    prime: bool,
-    method: String,
+/// Answers the requests of one connection. Requests are parsed and called on
+/// the blocking pool, several at once, and answered in the order they came
+/// in. Malformed requests, lines that aren't UTF-8 among them, are answered
+/// with an error object. If reading fails, the requests read so far are still
+/// answered.
+///
+/// A request only starts once it gets one of the `jobs` permits shared by
+/// every connection, so requests still running for clients that are long gone
+/// count against the same limit as everyone else's. A connection only asks
+/// for as many of them at once as it is allowed to hold, so one sending many
+/// slow requests leaves the rest of the pool to the others.
+async fn handle<S: AsyncRead + AsyncWrite>(
+    stream: S,
+    registry: Arc<Registry>,
+    jobs: Jobs,
+    mode: Mode,
+) -> Result<()> {
+    let mine = Arc::new(Semaphore::new(jobs.per_connection));
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let mut in_flight = FuturesOrdered::new();
+    // Kept across iterations, as a read cut short by the other branch leaves
+    // what it got so far in here.
+    let mut line = vec![];
+    let mut reading = true;
+    let mut read_error = None;
+
+    loop {
+        tokio::select! {
+            read = reader.read_until(b'\n', &mut line), if reading && in_flight.len() < MAX_IN_FLIGHT => {
+                match read {
+                    Ok(0) => {
+                        reading = false;
+                        continue;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        reading = false;
+                        read_error = Some(e);
+                        continue;
+                    }
+                }
+                let mut line = std::mem::take(&mut line);
+                if line.ends_with(b"\n") {
+                    line.pop();
+                    if line.ends_with(b"\r") {
+                        line.pop();
+                    }
+                }
+                let registry = registry.clone();
+                let pool = jobs.pool.clone();
+                let mine = mine.clone();
+                in_flight.push_back(async move {
+                    let own = mine.acquire_owned().await.expect("jobs are never closed");
+                    let permit = pool.acquire_owned().await.expect("jobs are never closed");
+                    spawn_blocking(move || {
+                        let _permits = (own, permit);
+                        let line = String::from_utf8(line)
+                            .map_err(|e| Malformed::InvalidJson(e.to_string()))?;
+                        request::parse(&line).and_then(|request| registry.call(&request))
+                    })
+                    .await
+                });
+            }
+            Some(r) = in_flight.next() => {
+                let r = r?;
+                println!("{:?}", r);
+                match r {
+                    Ok(res) => {
+                        writer.write_all(res.as_bytes()).await?;
+                        writer.write_all(b"\n").await?;
+                        writer.flush().await?;
+                    }
+                    Err(e) => {
+                        writer.write_all(e.response().as_bytes()).await?;
+                        writer.write_all(b"\n").await?;
+                        writer.flush().await?;
+                        if mode == Mode::Strict {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+            else => return read_error.map_or(Ok(()), |e| Err(e.into())),
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let mut mode = Mode::Strict;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--strict" => mode = Mode::Strict,
+            "--lenient" => mode = Mode::Lenient,
+            _ => return Err(anyhow::Error::msg(format!("unknown argument {arg}"))),
+        }
+    }
+
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8000").await?;
+    let registry = Arc::new(Registry::default());
+    // One request per core at a time, whichever connections they come from,
+    // and no more than half of them for any one connection.
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let jobs = Jobs {
+        pool: Arc::new(Semaphore::new(cores)),
+        per_connection: (cores / 2).max(1),
+    };
     loop {
-        let mut socket = listener.accept().await?.0;
+        let socket = listener.accept().await?.0;
+        let registry = registry.clone();
+        let jobs = jobs.clone();
         tokio::spawn(async move {
-            let (reader, writer) = socket.split();
-            let mut reader = BufReader::new(reader);
-            let mut writer = BufWriter::new(writer);
-
-            loop {
-                let mut buffer = String::new();
-                match reader.read_line(&mut buffer).await {
-                    Ok(0) => return,
-                    Ok(_) => {
-                        let r: Result<Request, _> = serde_json::from_str(&buffer);
-                        println!("{:?}", r);
-                        match r {
-                            Ok(request) => {
-                                let res = Response {
-                                    prime: is_prime(request.number),
-                                    method: request.method,
-                                };
-                                if res.method != "isPrime".to_string() {
-                                    writer.write_all(b"error").await.unwrap();
-                                    writer.flush().await.unwrap();
-                                    return;
-                                } else {
-                                    let res = serde_json::to_string(&res).unwrap();
-                                    writer.write_all(res.as_bytes()).await.unwrap();
-                                    writer.write_all(b"\n").await.unwrap();
-                                    writer.flush().await.unwrap();
-                                }
-                            }
-                            _ => {
-                                writer.write_all(b"error").await.unwrap();
-                                writer.flush().await.unwrap();
-                                return;
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Error: {}", e);
-                        return;
-                    }
-                }
+            if let Err(e) = handle(socket, registry, jobs, mode).await {
+                eprintln!("Error: {}", e);
             }
         });
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, ReadBuf};
+    use tokio::time::timeout;
+
+    fn jobs() -> Jobs {
+        Jobs {
+            pool: Arc::new(Semaphore::new(4)),
+            per_connection: 2,
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_in_order() {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        tokio::spawn(handle(
+            server,
+            Arc::new(Registry::default()),
+            jobs(),
+            Mode::Strict,
+        ));
+        let (mut reader, mut writer) = tokio::io::split(client);
+
+        // A slow count first, then many quick checks behind it.
+        let mut requests = String::from("{\"method\":\"primeCount\",\"number\":3000000}\n");
+        for n in 0..100 {
+            requests += &format!("{{\"method\":\"isPrime\",\"number\":{n}}}\n");
+        }
+        requests += "{\"method\":\"isPrime\"}\n";
+        writer.write_all(requests.as_bytes()).await.unwrap();
+
+        let mut responses = String::new();
+        reader.read_to_string(&mut responses).await.unwrap();
+        let mut responses = responses.split('\n');
+        assert_eq!(
+            responses.next(),
+            Some(r#"{"method":"primeCount","count":216816}"#)
+        );
+        for n in 0..100 {
+            let prime = prime::is_prime_u64(n);
+            let expected = format!("{{\"method\":\"isPrime\",\"prime\":{prime}}}");
+            assert_eq!(responses.next(), Some(expected.as_str()));
+        }
+        assert_eq!(
+            responses.next(),
+            Some(r#"{"error":{"code":"missing_field","message":"missing field `number`"}}"#)
+        );
+        assert_eq!(responses.next(), Some(""));
+        assert_eq!(responses.next(), None);
+    }
+
+    #[tokio::test]
+    async fn requests_wait_for_a_job() {
+        let jobs = Jobs {
+            pool: Arc::new(Semaphore::new(1)),
+            per_connection: 1,
+        };
+        let (client, server) = tokio::io::duplex(1 << 16);
+        tokio::spawn(handle(
+            server,
+            Arc::new(Registry::default()),
+            jobs.clone(),
+            Mode::Strict,
+        ));
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut lines = BufReader::new(reader).lines();
+
+        // Another connection is using the only job.
+        let permit = jobs.pool.clone().acquire_owned().await.unwrap();
+        writer
+            .write_all(b"{\"method\":\"isPrime\",\"number\":7}\n")
+            .await
+            .unwrap();
+        let wait = Duration::from_millis(100);
+        assert!(timeout(wait, lines.next_line()).await.is_err());
+        drop(permit);
+        assert_eq!(
+            lines.next_line().await.unwrap().as_deref(),
+            Some(r#"{"method":"isPrime","prime":true}"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn connection_leaves_jobs_to_others() {
+        let jobs = Jobs {
+            pool: Arc::new(Semaphore::new(2)),
+            per_connection: 1,
+        };
+        let registry = Arc::new(Registry::default());
+        let (slow, server) = tokio::io::duplex(1 << 16);
+        tokio::spawn(handle(server, registry.clone(), jobs.clone(), Mode::Strict));
+        let (quick, server) = tokio::io::duplex(1 << 16);
+        tokio::spawn(handle(server, registry, jobs.clone(), Mode::Strict));
+        let (slow_reader, mut slow_writer) = tokio::io::split(slow);
+        let mut slow_lines = BufReader::new(slow_reader).lines();
+        let (quick_reader, mut quick_writer) = tokio::io::split(quick);
+        let mut quick_lines = BufReader::new(quick_reader).lines();
+
+        slow_writer
+            .write_all(&b"{\"method\":\"primeCount\",\"number\":30000000}\n".repeat(4))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(jobs.pool.available_permits(), 1);
+
+        quick_writer
+            .write_all(b"{\"method\":\"isPrime\",\"number\":7}\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            quick_lines.next_line().await.unwrap().as_deref(),
+            Some(r#"{"method":"isPrime","prime":true}"#)
+        );
+        // Answered while the first slow count was still running.
+        let wait = Duration::from_millis(0);
+        assert!(timeout(wait, slow_lines.next_line()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn lenient_mode_goes_on() {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        tokio::spawn(handle(
+            server,
+            Arc::new(Registry::default()),
+            jobs(),
+            Mode::Lenient,
+        ));
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut lines = BufReader::new(reader).lines();
+
+        writer
+            .write_all(b"{\"method\":\"isPrime\",\"number\":\"7\"}\nnot json\n{\"method\":\"isPrime\",\"number\":7}\n")
+            .await
+            .unwrap();
+        let mut codes = vec![];
+        for _ in 0..2 {
+            let line = lines.next_line().await.unwrap().unwrap();
+            let response: serde_json::Value = serde_json::from_str(&line).unwrap();
+            codes.push(response["error"]["code"].as_str().unwrap().to_owned());
+        }
+        assert_eq!(codes, ["invalid_number", "invalid_json"]);
+        assert_eq!(
+            lines.next_line().await.unwrap().as_deref(),
+            Some(r#"{"method":"isPrime","prime":true}"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_utf8_is_invalid_json() {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        tokio::spawn(handle(
+            server,
+            Arc::new(Registry::default()),
+            jobs(),
+            Mode::Strict,
+        ));
+        let (mut reader, mut writer) = tokio::io::split(client);
+
+        writer
+            .write_all(b"{\"method\":\"isPrime\",\"number\":7}\r\n{\"method\":\"\xff\"}\n")
+            .await
+            .unwrap();
+        let mut responses = String::new();
+        reader.read_to_string(&mut responses).await.unwrap();
+        let responses: Vec<_> = responses.lines().collect();
+        assert_eq!(responses[0], r#"{"method":"isPrime","prime":true}"#);
+        let error: serde_json::Value = serde_json::from_str(responses[1]).unwrap();
+        assert_eq!(error["error"]["code"], "invalid_json");
+        assert_eq!(responses.len(), 2);
+    }
+
+    /// A connection that breaks whenever it is read from.
+    struct Broken;
+
+    impl AsyncRead for Broken {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+        }
+    }
+
+    #[tokio::test]
+    async fn answer_before_read_error() {
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let requests = &b"{\"method\":\"primeCount\",\"number\":100}\n"[..];
+        let stream = tokio::io::join(requests.chain(Broken), server);
+        let result = handle(stream, Arc::new(Registry::default()), jobs(), Mode::Strict).await;
+        assert!(result.is_err());
+
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).await.unwrap();
+        assert_eq!(responses, "{\"method\":\"primeCount\",\"count\":25}\n");
+    }
+}
@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+
+use num_bigint::{BigInt, BigUint, Sign};
+use num_traits::ToPrimitive;
+use serde::{Serialize, Serializer};
+use serde_json::value::RawValue;
+
+use crate::number::Number;
+use crate::prime;
+use crate::request::{Fields, Malformed, Request};
+
+// Largest number primeCount sieves up to, which takes about a second.
+const MAX_PRIME_COUNT: u64 = 1_000_000_000;
+// Digits of the numbers isPrime and isProbablePrime test, each round of which
+// takes up to 50ms on numbers this long.
+const MAX_TEST_DIGITS: u32 = 1000;
+// Digits of the numbers nextPrime and prevPrime search from, which test
+// hundreds of candidates.
+const MAX_SEARCH_DIGITS: u32 = 300;
+// Rounds isProbablePrime does unless asked otherwise, and at most.
+const DEFAULT_ROUNDS: u32 = 20;
+const MAX_ROUNDS: u32 = 1000;
+
+/// Parameters of a method, read from the fields of its request.
+pub trait Params: Sized {
+    fn from_fields(fields: &Fields) -> Result<Self, Malformed>;
+}
+
+/// A method requests can call by name. Its result is sent back with the
+/// method name, as the fields of one JSON object.
+pub trait Method {
+    const NAME: &'static str;
+    type Params: Params;
+    type Output: Serialize;
+
+    fn call(params: Self::Params) -> Result<Self::Output, Malformed>;
+}
+
+#[derive(Serialize)]
+struct Response<R> {
+    method: &'static str,
+    #[serde(flatten)]
+    result: R,
+}
+
+type Handler = fn(&Fields) -> Result<String, Malformed>;
+
+fn handle<M: Method>(fields: &Fields) -> Result<String, Malformed> {
+    let params = M::Params::from_fields(fields)?;
+    let response = Response {
+        method: M::NAME,
+        result: M::call(params)?,
+    };
+    Ok(serde_json::to_string(&response).expect("responses serialize"))
+}
+
+/// The methods requests can call, by name.
+pub struct Registry {
+    methods: HashMap<&'static str, Handler>,
+}
+
+impl Registry {
+    /// A registry without any methods.
+    pub fn new() -> Registry {
+        Registry {
+            methods: HashMap::new(),
+        }
+    }
+
+    pub fn register<M: Method>(&mut self) -> &mut Registry {
+        self.methods.insert(M::NAME, handle::<M>);
+        self
+    }
+
+    /// Calls the method of `request`, giving the response line without the
+    /// newline.
+    pub fn call(&self, request: &Request) -> Result<String, Malformed> {
+        let handler = self
+            .methods
+            .get(request.method.as_str())
+            .ok_or_else(|| Malformed::UnknownMethod(request.method.clone()))?;
+        handler(&request.fields)
+    }
+}
+
+/// Every method this server has.
+impl Default for Registry {
+    fn default() -> Registry {
+        let mut registry = Registry::new();
+        registry
+            .register::<IsPrime>()
+            .register::<IsProbablePrime>()
+            .register::<Factorize>()
+            .register::<NextPrime>()
+            .register::<PrevPrime>()
+            .register::<PrimeCount>();
+        registry
+    }
+}
+
+/// An integer, written out in full however large it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Integer(pub BigUint);
+
+impl Serialize for Integer {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let literal = RawValue::from_string(self.0.to_string()).expect("digits are JSON");
+        literal.serialize(serializer)
+    }
+}
+
+/// Fails unless `n` has at most `max` digits.
+fn at_most_digits(n: &BigUint, max: u32) -> Result<(), Malformed> {
+    if *n >= BigUint::from(10u32).pow(max) {
+        return Err(Malformed::InvalidParams(format!(
+            "`number` has more than {max} digits"
+        )));
+    }
+    Ok(())
+}
+
+fn integer(number: Number, name: &str) -> Result<BigInt, Malformed> {
+    number
+        .integer()
+        .ok_or_else(|| Malformed::InvalidParams(format!("`{name}` is not an integer")))
+}
+
+/// Any number.
+pub struct NumberParams {
+    pub number: Number,
+}
+
+impl Params for NumberParams {
+    fn from_fields(fields: &Fields) -> Result<Self, Malformed> {
+        Ok(NumberParams {
+            number: fields.number("number")?,
+        })
+    }
+}
+
+/// Any integer, negative ones too.
+pub struct IntegerParams {
+    pub number: BigInt,
+}
+
+impl Params for IntegerParams {
+    fn from_fields(fields: &Fields) -> Result<Self, Malformed> {
+        Ok(IntegerParams {
+            number: integer(fields.number("number")?, "number")?,
+        })
+    }
+}
+
+pub struct ProbablePrimeParams {
+    pub number: Number,
+    pub rounds: u32,
+}
+
+impl Params for ProbablePrimeParams {
+    fn from_fields(fields: &Fields) -> Result<Self, Malformed> {
+        let rounds = match fields.optional_number("rounds")? {
+            Some(rounds) => integer(rounds, "rounds")?
+                .to_u32()
+                .filter(|rounds| (1..=MAX_ROUNDS).contains(rounds))
+                .ok_or_else(|| {
+                    Malformed::InvalidParams(format!("`rounds` is not from 1 to {MAX_ROUNDS}"))
+                })?,
+            None => DEFAULT_ROUNDS,
+        };
+        Ok(ProbablePrimeParams {
+            number: fields.number("number")?,
+            rounds,
+        })
+    }
+}
+
+/// A 64-bit integer of 1 or more.
+pub struct FactorizeParams {
+    pub number: u64,
+}
+
+impl Params for FactorizeParams {
+    fn from_fields(fields: &Fields) -> Result<Self, Malformed> {
+        let number = integer(fields.number("number")?, "number")?
+            .to_u64()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| {
+                Malformed::InvalidParams("`number` is not from 1 to 2^64 - 1".to_string())
+            })?;
+        Ok(FactorizeParams { number })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PrimeResult {
+    pub prime: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FactorsResult {
+    pub factors: Vec<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NumberResult {
+    /// `null` if there is no such number.
+    pub number: Option<Integer>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CountResult {
+    pub count: u64,
+}
+
+/// Whether a number is prime, exactly for 64-bit numbers.
+pub struct IsPrime;
+
+impl Method for IsPrime {
+    const NAME: &'static str = "isPrime";
+    type Params = NumberParams;
+    type Output = PrimeResult;
+
+    fn call(params: NumberParams) -> Result<PrimeResult, Malformed> {
+        let prime = match params.number {
+            Number::Natural(n) => {
+                at_most_digits(&n, MAX_TEST_DIGITS)?;
+                prime::is_prime(&n)
+            }
+            // Neither negative numbers nor fractions can be prime.
+            Number::Negative(_) | Number::Fractional(_) => false,
+        };
+        Ok(PrimeResult { prime })
+    }
+}
+
+/// Miller-Rabin with a chosen number of random bases.
+pub struct IsProbablePrime;
+
+impl Method for IsProbablePrime {
+    const NAME: &'static str = "isProbablePrime";
+    type Params = ProbablePrimeParams;
+    type Output = PrimeResult;
+
+    fn call(params: ProbablePrimeParams) -> Result<PrimeResult, Malformed> {
+        let prime = match params.number {
+            Number::Natural(n) => {
+                at_most_digits(&n, MAX_TEST_DIGITS)?;
+                prime::is_probable_prime(&n, params.rounds)
+            }
+            Number::Negative(_) | Number::Fractional(_) => false,
+        };
+        Ok(PrimeResult { prime })
+    }
+}
+
+pub struct Factorize;
+
+impl Method for Factorize {
+    const NAME: &'static str = "factorize";
+    type Params = FactorizeParams;
+    type Output = FactorsResult;
+
+    fn call(params: FactorizeParams) -> Result<FactorsResult, Malformed> {
+        Ok(FactorsResult {
+            factors: prime::factorize(params.number),
+        })
+    }
+}
+
+/// The smallest prime above the number.
+pub struct NextPrime;
+
+impl Method for NextPrime {
+    const NAME: &'static str = "nextPrime";
+    type Params = IntegerParams;
+    type Output = NumberResult;
+
+    fn call(params: IntegerParams) -> Result<NumberResult, Malformed> {
+        // Everything below zero is followed by 2, as is 0.
+        let n = params.number.to_biguint().unwrap_or_default();
+        at_most_digits(&n, MAX_SEARCH_DIGITS)?;
+        Ok(NumberResult {
+            number: Some(Integer(prime::next_prime(&n))),
+        })
+    }
+}
+
+/// The largest prime below the number.
+pub struct PrevPrime;
+
+impl Method for PrevPrime {
+    const NAME: &'static str = "prevPrime";
+    type Params = IntegerParams;
+    type Output = NumberResult;
+
+    fn call(params: IntegerParams) -> Result<NumberResult, Malformed> {
+        let n = params.number.to_biguint().unwrap_or_default();
+        at_most_digits(&n, MAX_SEARCH_DIGITS)?;
+        Ok(NumberResult {
+            number: prime::prev_prime(&n).map(Integer),
+        })
+    }
+}
+
+/// How many primes there are up to the number.
+pub struct PrimeCount;
+
+impl Method for PrimeCount {
+    const NAME: &'static str = "primeCount";
+    type Params = IntegerParams;
+    type Output = CountResult;
+
+    fn call(params: IntegerParams) -> Result<CountResult, Malformed> {
+        if params.number.sign() == Sign::Minus {
+            return Ok(CountResult { count: 0 });
+        }
+        let n = params
+            .number
+            .to_u64()
+            .filter(|&n| n <= MAX_PRIME_COUNT)
+            .ok_or_else(|| {
+                Malformed::InvalidParams(format!("`number` is above {MAX_PRIME_COUNT}"))
+            })?;
+        Ok(CountResult {
+            count: prime::prime_count(n),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::request;
+
+    fn call(line: &str) -> Result<String, Malformed> {
+        Registry::default().call(&request::parse(line)?)
+    }
+
+    #[test]
+    fn call_methods() {
+        let cases = [
+            (
+                r#"{"method":"isPrime","number":7}"#,
+                r#"{"method":"isPrime","prime":true}"#,
+            ),
+            (
+                r#"{"method":"isPrime","number":7.5}"#,
+                r#"{"method":"isPrime","prime":false}"#,
+            ),
+            (
+                r#"{"method":"isProbablePrime","number":170141183460469231731687303715884105727,"rounds":5}"#,
+                r#"{"method":"isProbablePrime","prime":true}"#,
+            ),
+            (
+                r#"{"method":"factorize","number":360}"#,
+                r#"{"method":"factorize","factors":[2,2,2,3,3,5]}"#,
+            ),
+            (
+                r#"{"method":"factorize","number":1}"#,
+                r#"{"method":"factorize","factors":[]}"#,
+            ),
+            (
+                r#"{"method":"nextPrime","number":18446744073709551557}"#,
+                r#"{"method":"nextPrime","number":18446744073709551629}"#,
+            ),
+            (
+                r#"{"method":"nextPrime","number":-7}"#,
+                r#"{"method":"nextPrime","number":2}"#,
+            ),
+            (
+                r#"{"method":"prevPrime","number":2}"#,
+                r#"{"method":"prevPrime","number":null}"#,
+            ),
+            (
+                r#"{"method":"prevPrime","number":1e2}"#,
+                r#"{"method":"prevPrime","number":97}"#,
+            ),
+            (
+                r#"{"method":"primeCount","number":100}"#,
+                r#"{"method":"primeCount","count":25}"#,
+            ),
+            (
+                r#"{"method":"primeCount","number":-100}"#,
+                r#"{"method":"primeCount","count":0}"#,
+            ),
+        ];
+        for (line, response) in cases {
+            assert_eq!(call(line).as_deref(), Ok(response), "{line}");
+        }
+    }
+
+    #[test]
+    fn large_numbers() {
+        // 2^1279 - 1, a Mersenne prime of 386 digits.
+        let mersenne = (BigUint::from(1u32) << 1279u32) - 1u32;
+        for method in ["isPrime", "isProbablePrime"] {
+            let line = format!(r#"{{"method":"{method}","number":{mersenne}}}"#);
+            let response = format!(r#"{{"method":"{method}","prime":true}}"#);
+            assert_eq!(call(&line), Ok(response));
+        }
+        assert_eq!(
+            call(r#"{"method":"isPrime","number":1e999}"#).as_deref(),
+            Ok(r#"{"method":"isPrime","prime":false}"#)
+        );
+        // A number that large can be written out but not searched from.
+        let e = call(&format!(r#"{{"method":"nextPrime","number":{mersenne}}}"#)).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "invalid params: `number` has more than 300 digits"
+        );
+    }
+
+    #[test]
+    fn invalid_params() {
+        let cases = [
+            (r#"{"method":"isPrime"}"#, "missing field `number`"),
+            (
+                r#"{"method":"isprime","number":7}"#,
+                "unknown method \"isprime\"",
+            ),
+            (
+                r#"{"method":"isPrime","number":"7"}"#,
+                "invalid number: not a number",
+            ),
+            (
+                r#"{"method":"isPrime","number":null}"#,
+                "invalid number: not a number",
+            ),
+            (
+                r#"{"method":"isPrime","number":true}"#,
+                "invalid number: not a number",
+            ),
+            (r#"{"method":"factorize","number":0}"#, "invalid params"),
+            (r#"{"method":"factorize","number":2.5}"#, "invalid params"),
+            (
+                r#"{"method":"factorize","number":18446744073709551616}"#,
+                "invalid params",
+            ),
+            (
+                r#"{"method":"isProbablePrime","number":7,"rounds":0}"#,
+                "invalid params",
+            ),
+            (r#"{"method":"primeCount","number":1e10}"#, "invalid params"),
+            (r#"{"method":"isPrime","number":1e1000}"#, "invalid params"),
+            (r#"{"method":"prevPrime","number":1e300}"#, "invalid params"),
+        ];
+        for (line, reason) in cases {
+            let e = call(line).unwrap_err();
+            assert!(e.to_string().starts_with(reason), "{line}: {e}");
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::fmt;
+
+use num_bigint::{BigInt, BigUint};
+use num_traits::Zero;
+
+// Digits an integer may have, written out or with an exponent. Methods that
+// would take too long on numbers this large limit them further themselves.
+const MAX_DIGITS: usize = 100_000;
+
+/// A JSON number, exactly as it was written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Number {
+    /// An integer of zero or above, however large. `7.0` and `7e0` are
+    /// integers too.
+    Natural(BigUint),
+    /// An integer below zero.
+    Negative(BigInt),
+    /// A number with a fractional part, as written.
+    Fractional(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NumberError {
+    /// Not a JSON number at all, such as a string or a boolean.
+    NotANumber,
+    /// An integer with more than [`MAX_DIGITS`] digits.
+    TooLarge,
+}
+
+impl fmt::Display for NumberError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NumberError::NotANumber => write!(f, "not a number"),
+            NumberError::TooLarge => write!(f, "more than {MAX_DIGITS} digits"),
+        }
+    }
+}
+
+impl std::error::Error for NumberError {}
+
+fn all_digits(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+impl Number {
+    /// Parses a JSON number literal without rounding it.
+    pub fn parse(literal: &str) -> Result<Number, NumberError> {
+        let (negative, unsigned) = match literal.strip_prefix('-') {
+            Some(unsigned) => (true, unsigned),
+            None => (false, literal),
+        };
+        let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+            Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+            None => (unsigned, None),
+        };
+        let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        let leading_zero = int.len() > 1 && int.starts_with('0');
+        if !all_digits(int) || leading_zero || (mantissa.contains('.') && !all_digits(frac)) {
+            return Err(NumberError::NotANumber);
+        }
+
+        let mut digits = format!("{int}{frac}");
+        // Power of ten the digits are multiplied by
+        let mut scale = -(frac.len() as i64);
+        if let Some(exponent) = exponent {
+            let unsigned = exponent.trim_start_matches(['+', '-']);
+            if !all_digits(unsigned) || exponent.len() - unsigned.len() > 1 {
+                return Err(NumberError::NotANumber);
+            }
+            // Exponents too large for an i64 are well past any limit anyway.
+            let exponent: i64 = exponent.parse().unwrap_or(if exponent.starts_with('-') {
+                i64::MIN / 2
+            } else {
+                i64::MAX / 2
+            });
+            scale += exponent;
+        }
+
+        let trimmed = digits.trim_start_matches('0');
+        if trimmed.is_empty() {
+            return Ok(Number::Natural(BigUint::zero()));
+        }
+        digits = trimmed.to_owned();
+        while scale < 0 && digits.ends_with('0') {
+            digits.pop();
+            scale += 1;
+        }
+        if scale < 0 {
+            return Ok(Number::Fractional(literal.to_owned()));
+        }
+        if digits.len() as i64 + scale > MAX_DIGITS as i64 {
+            return Err(NumberError::TooLarge);
+        }
+
+        let n: BigUint = digits.parse().expect("only digits are left");
+        let n = n * BigUint::from(10u32).pow(scale as u32);
+        if negative {
+            Ok(Number::Negative(-BigInt::from(n)))
+        } else {
+            Ok(Number::Natural(n))
+        }
+    }
+
+    /// The number as an integer, unless it has a fractional part.
+    pub fn integer(&self) -> Option<BigInt> {
+        match self {
+            Number::Natural(n) => Some(BigInt::from(n.clone())),
+            Number::Negative(n) => Some(n.clone()),
+            Number::Fractional(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn natural(n: &str) -> Number {
+        Number::Natural(n.parse().unwrap())
+    }
+
+    #[test]
+    fn parse_integers() {
+        assert_eq!(Number::parse("7"), Ok(natural("7")));
+        assert_eq!(Number::parse("7.0"), Ok(natural("7")));
+        assert_eq!(Number::parse("0.7e1"), Ok(natural("7")));
+        assert_eq!(Number::parse("700e-2"), Ok(natural("7")));
+        assert_eq!(Number::parse("-0.0"), Ok(natural("0")));
+        assert_eq!(
+            Number::parse("340282366920938463463374607431768211457"),
+            Ok(natural("340282366920938463463374607431768211457"))
+        );
+        assert_eq!(Number::parse("1E3"), Ok(natural("1000")));
+        assert_eq!(Number::parse("-7"), Ok(Number::Negative(BigInt::from(-7))));
+    }
+
+    #[test]
+    fn parse_fractions() {
+        assert_eq!(
+            Number::parse("7.5"),
+            Ok(Number::Fractional("7.5".to_string()))
+        );
+        assert_eq!(
+            Number::parse("-1e-400"),
+            Ok(Number::Fractional("-1e-400".to_string()))
+        );
+        assert_eq!(
+            Number::parse("1e-99999999999999999999"),
+            Ok(Number::Fractional("1e-99999999999999999999".to_string()))
+        );
+    }
+
+    #[test]
+    fn reject_other_values() {
+        for literal in [
+            "\"7\"", "true", "null", "[7]", "", "-", "07", "7.", ".5", "1e", "1e+-2",
+        ] {
+            assert_eq!(
+                Number::parse(literal),
+                Err(NumberError::NotANumber),
+                "{literal}"
+            );
+        }
+        assert!(Number::parse(&"9".repeat(MAX_DIGITS)).is_ok());
+        assert_eq!(Number::parse("1e100000"), Err(NumberError::TooLarge));
+        assert_eq!(
+            Number::parse(&"9".repeat(MAX_DIGITS + 1)),
+            Err(NumberError::TooLarge)
+        );
+    }
+}
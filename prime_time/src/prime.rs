@@ -0,0 +1,342 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use num_bigint::BigUint;
+use num_traits::{One, ToPrimitive, Zero};
+
+// The first 13 primes. Miller-Rabin with the first 12 as bases is exact for
+// every 64-bit number, and with all 13 below 3.3 * 10^24.
+const BASES: [u64; 13] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41];
+
+fn mul_mod(a: u64, b: u64, m: u64) -> u64 {
+    (a as u128 * b as u128 % m as u128) as u64
+}
+
+fn pow_mod(mut base: u64, mut exp: u64, m: u64) -> u64 {
+    let mut result = 1;
+    base %= m;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = mul_mod(result, base, m);
+        }
+        base = mul_mod(base, base, m);
+        exp >>= 1;
+    }
+    result
+}
+
+/// Whether odd `n > 2` is a strong probable prime to base `a`.
+fn strong_probable_prime_u64(n: u64, a: u64) -> bool {
+    let s = (n - 1).trailing_zeros();
+    let d = (n - 1) >> s;
+    let mut x = pow_mod(a, d, n);
+    if x == 1 || x == n - 1 {
+        return true;
+    }
+    for _ in 1..s {
+        x = mul_mod(x, x, n);
+        if x == n - 1 {
+            return true;
+        }
+    }
+    false
+}
+
+/// Deterministic Miller-Rabin for 64-bit numbers.
+pub fn is_prime_u64(n: u64) -> bool {
+    if n < 2 {
+        return false;
+    }
+    for p in BASES {
+        if n.is_multiple_of(p) {
+            return n == p;
+        }
+    }
+    BASES[..12].iter().all(|&a| strong_probable_prime_u64(n, a))
+}
+
+/// Whether odd `n > 2` is a strong probable prime to base `a`.
+pub fn strong_probable_prime(n: &BigUint, a: &BigUint) -> bool {
+    let one = BigUint::one();
+    let n_minus_one = n - &one;
+    let s = n_minus_one.trailing_zeros().unwrap_or(0);
+    let d = &n_minus_one >> s;
+    let mut x = a.modpow(&d, n);
+    if x == one || x == n_minus_one {
+        return true;
+    }
+    for _ in 1..s {
+        x = &x * &x % n;
+        if x == n_minus_one {
+            return true;
+        }
+    }
+    false
+}
+
+/// Whether `n` is prime. This is exact for 64-bit numbers. Larger ones must
+/// be strong probable primes to the first 13 prime bases, which no composite
+/// below 3.3 * 10^24 is.
+pub fn is_prime(n: &BigUint) -> bool {
+    if let Some(n) = n.to_u64() {
+        return is_prime_u64(n);
+    }
+    if BASES.iter().any(|&p| (n % p).is_zero()) {
+        return false;
+    }
+    BASES
+        .iter()
+        .all(|&a| strong_probable_prime(n, &BigUint::from(a)))
+}
+
+/// Whether `n` is a strong probable prime to `rounds` random bases. A
+/// composite passes each round with a chance of at most 1 in 4.
+pub fn is_probable_prime(n: &BigUint, rounds: u32) -> bool {
+    if let Some(n) = n.to_u64() {
+        if n < 4 || n.is_multiple_of(2) {
+            return n == 2 || n == 3;
+        }
+    } else if !n.bit(0) {
+        return false;
+    }
+    let mut rng = Rng::new();
+    let span = n - 3u32;
+    (0..rounds).all(|_| {
+        // Bases from 2 to n - 2
+        let a = rng.below(&span) + 2u32;
+        strong_probable_prime(n, &a)
+    })
+}
+
+/// The smallest prime above `n`.
+pub fn next_prime(n: &BigUint) -> BigUint {
+    if *n < BigUint::from(2u32) {
+        return BigUint::from(2u32);
+    }
+    let mut candidate = n + 1u32;
+    if !candidate.bit(0) {
+        candidate += 1u32;
+    }
+    while !is_prime(&candidate) {
+        candidate += 2u32;
+    }
+    candidate
+}
+
+/// The largest prime below `n`, if there is one.
+pub fn prev_prime(n: &BigUint) -> Option<BigUint> {
+    if *n <= BigUint::from(3u32) {
+        return (*n == BigUint::from(3u32)).then(|| BigUint::from(2u32));
+    }
+    let mut candidate = n - 1u32;
+    if !candidate.bit(0) {
+        candidate -= 1u32;
+    }
+    // Stops at 3 at the latest.
+    while !is_prime(&candidate) {
+        candidate -= 2u32;
+    }
+    Some(candidate)
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// A nontrivial factor of odd composite `n`, by Pollard's rho.
+fn pollard_rho(n: u64) -> u64 {
+    let step = |x: u64, c: u64| ((x as u128 * x as u128 + c as u128) % n as u128) as u64;
+    for c in 1.. {
+        let (mut x, mut y, mut d) = (2, 2, 1);
+        while d == 1 {
+            x = step(x, c);
+            y = step(step(y, c), c);
+            d = gcd(x.abs_diff(y), n);
+        }
+        if d != n {
+            return d;
+        }
+    }
+    unreachable!("some c finds a factor")
+}
+
+/// The prime factors of `n`, smallest first and repeated as often as they
+/// divide it. 1 has none.
+pub fn factorize(mut n: u64) -> Vec<u64> {
+    let mut factors = vec![];
+    for p in BASES {
+        while n.is_multiple_of(p) && n > 1 {
+            factors.push(p);
+            n /= p;
+        }
+    }
+    let mut composites = vec![n];
+    while let Some(n) = composites.pop() {
+        if n == 1 {
+            continue;
+        }
+        if is_prime_u64(n) {
+            factors.push(n);
+            continue;
+        }
+        let d = pollard_rho(n);
+        composites.extend([d, n / d]);
+    }
+    factors.sort_unstable();
+    factors
+}
+
+/// How many primes there are up to and including `n`, by a segmented
+/// sieve of Eratosthenes.
+pub fn prime_count(n: u64) -> u64 {
+    const SEGMENT: u64 = 1 << 16;
+    if n < 2 {
+        return 0;
+    }
+    let root = n.isqrt();
+    let mut composite = vec![false; root as usize + 1];
+    let mut primes = vec![];
+    for p in 2..=root {
+        if !composite[p as usize] {
+            primes.push(p);
+            for multiple in (p * p..=root).step_by(p as usize) {
+                composite[multiple as usize] = true;
+            }
+        }
+    }
+
+    let mut count = 0;
+    let mut low = 2;
+    while low <= n {
+        let high = n.min(low + SEGMENT - 1);
+        let mut composite = vec![false; (high - low + 1) as usize];
+        for &p in &primes {
+            let first = (p * p).max(low.div_ceil(p) * p);
+            for multiple in (first..=high).step_by(p as usize) {
+                composite[(multiple - low) as usize] = true;
+            }
+        }
+        count += composite.iter().filter(|&&c| !c).count() as u64;
+        low = high + 1;
+    }
+    count
+}
+
+/// A xorshift generator seeded by the std hasher's random keys. Good enough
+/// to pick Miller-Rabin bases.
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Rng {
+        let seed = RandomState::new().build_hasher().finish();
+        Rng(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Roughly uniform below `n`, which must not be zero.
+    fn below(&mut self, n: &BigUint) -> BigUint {
+        let words = n.bits() / 64 + 2;
+        let digits: Vec<u32> = (0..words * 2).map(|_| self.next() as u32).collect();
+        BigUint::new(digits) % n
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn big(n: &str) -> BigUint {
+        n.parse().unwrap()
+    }
+
+    #[test]
+    fn small_numbers() {
+        let primes: Vec<u64> = (0..60).filter(|&n| is_prime_u64(n)).collect();
+        assert_eq!(
+            primes,
+            [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59]
+        );
+    }
+
+    #[test]
+    fn sixty_four_bit_numbers() {
+        // The largest 64-bit prime and the Mersenne prime 2^61 - 1
+        assert!(is_prime_u64(18446744073709551557));
+        assert!(is_prime_u64((1 << 61) - 1));
+        // A Carmichael number and a strong pseudoprime to bases 2, 3, 5 and 7
+        assert!(!is_prime_u64(561));
+        assert!(!is_prime_u64(3215031751));
+        assert!(!is_prime_u64(u64::MAX));
+    }
+
+    #[test]
+    fn huge_numbers() {
+        // 2^89 - 1 and 2^127 - 1
+        assert!(is_prime(&big("618970019642690137449562111")));
+        assert!(is_prime(&big("170141183460469231731687303715884105727")));
+        // 2^64 + 1 = 274177 * 67280421310721
+        assert!(!is_prime(&big("18446744073709551617")));
+        // A strong pseudoprime to the first 12 prime bases
+        assert!(!is_prime(&big("318665857834031151167461")));
+    }
+
+    #[test]
+    fn probable_primes() {
+        assert!(is_probable_prime(
+            &big("170141183460469231731687303715884105727"),
+            20
+        ));
+        assert!(!is_probable_prime(&big("318665857834031151167461"), 20));
+        let primes: Vec<u32> = (0..20u32)
+            .filter(|&n| is_probable_prime(&BigUint::from(n), 5))
+            .collect();
+        assert_eq!(primes, [2, 3, 5, 7, 11, 13, 17, 19]);
+    }
+
+    #[test]
+    fn neighbouring_primes() {
+        assert_eq!(next_prime(&big("0")), big("2"));
+        assert_eq!(next_prime(&big("7")), big("11"));
+        // 2^64 + 13 is the first prime past 64 bits.
+        assert_eq!(
+            next_prime(&big("18446744073709551557")),
+            big("18446744073709551629")
+        );
+        assert_eq!(prev_prime(&big("2")), None);
+        assert_eq!(prev_prime(&big("3")), Some(big("2")));
+        assert_eq!(prev_prime(&big("11")), Some(big("7")));
+        assert_eq!(
+            prev_prime(&big("18446744073709551629")),
+            Some(big("18446744073709551557"))
+        );
+    }
+
+    #[test]
+    fn factors() {
+        assert_eq!(factorize(1), [0u64; 0]);
+        assert_eq!(factorize(360), [2, 2, 2, 3, 3, 5]);
+        assert_eq!(factorize(3215031751), [151, 751, 28351]);
+        // 2^64 - 1
+        assert_eq!(factorize(u64::MAX), [3, 5, 17, 257, 641, 65537, 6700417]);
+        assert_eq!(factorize(18446744073709551557), [18446744073709551557]);
+        // Two 32-bit primes
+        assert_eq!(factorize(4294967291 * 4294967279), [4294967279, 4294967291]);
+    }
+
+    #[test]
+    fn count_primes() {
+        assert_eq!(prime_count(1), 0);
+        assert_eq!(prime_count(2), 1);
+        assert_eq!(prime_count(100), 25);
+        assert_eq!(prime_count(1_000_000), 78498);
+    }
+}
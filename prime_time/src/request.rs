@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::de::{self, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::value::RawValue;
+
+use crate::number::{Number, NumberError};
+
+/// A well-formed request, for a method that may or may not exist.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// Every field, `method` included.
+    pub fields: Fields,
+}
+
+/// Why a line is not a well-formed request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Malformed {
+    /// The line is not JSON, or not a JSON object.
+    InvalidJson(String),
+    MissingField(&'static str),
+    /// `method` is not a string.
+    InvalidMethod,
+    UnknownMethod(String),
+    InvalidNumber(NumberError),
+    /// A number the method cannot take, such as a fraction to factorize.
+    InvalidParams(String),
+}
+
+impl fmt::Display for Malformed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Malformed::InvalidJson(e) => write!(f, "invalid JSON: {e}"),
+            Malformed::MissingField(field) => write!(f, "missing field `{field}`"),
+            Malformed::InvalidMethod => write!(f, "method is not a string"),
+            Malformed::UnknownMethod(method) => write!(f, "unknown method {method:?}"),
+            Malformed::InvalidNumber(e) => write!(f, "invalid number: {e}"),
+            Malformed::InvalidParams(e) => write!(f, "invalid params: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Malformed {}
+
+#[derive(Serialize)]
+struct ErrorResponse<'a> {
+    error: ErrorBody<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: String,
+}
+
+impl Malformed {
+    /// What kind of problem this is, for clients to match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Malformed::InvalidJson(_) => "invalid_json",
+            Malformed::MissingField(_) => "missing_field",
+            Malformed::InvalidMethod => "invalid_method",
+            Malformed::UnknownMethod(_) => "unknown_method",
+            Malformed::InvalidNumber(_) => "invalid_number",
+            Malformed::InvalidParams(_) => "invalid_params",
+        }
+    }
+
+    /// The response line, without the newline. It has no `method` field, so
+    /// clients cannot take it for an answer.
+    pub fn response(&self) -> String {
+        let response = ErrorResponse {
+            error: ErrorBody {
+                code: self.code(),
+                message: self.to_string(),
+            },
+        };
+        serde_json::to_string(&response).expect("errors serialize")
+    }
+}
+
+/// The fields of a request object, not interpreted yet. `null` fields are
+/// kept, so they are told apart from missing ones.
+#[derive(Debug, Clone, Default)]
+pub struct Fields(HashMap<String, Box<RawValue>>);
+
+impl Fields {
+    pub fn get(&self, name: &str) -> Option<&RawValue> {
+        self.0.get(name).map(|value| &**value)
+    }
+
+    /// The number in field `name`, which must be there.
+    pub fn number(&self, name: &'static str) -> Result<Number, Malformed> {
+        self.optional_number(name)?
+            .ok_or(Malformed::MissingField(name))
+    }
+
+    /// The number in field `name`, if there is one.
+    pub fn optional_number(&self, name: &str) -> Result<Option<Number>, Malformed> {
+        self.get(name)
+            .map(|value| Number::parse(value.get()).map_err(Malformed::InvalidNumber))
+            .transpose()
+    }
+}
+
+impl<'de> Deserialize<'de> for Fields {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FieldsVisitor;
+
+        impl<'de> Visitor<'de> for FieldsVisitor {
+            type Value = Fields;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a JSON object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Fields, A::Error> {
+                let mut fields = HashMap::new();
+                while let Some(name) = map.next_key::<String>()? {
+                    let value: Box<RawValue> = map.next_value()?;
+                    if fields.contains_key(&name) {
+                        return Err(de::Error::custom(format!("duplicate field `{name}`")));
+                    }
+                    fields.insert(name, value);
+                }
+                Ok(Fields(fields))
+            }
+        }
+
+        deserializer.deserialize_map(FieldsVisitor)
+    }
+}
+
+/// Parses a request line. Which method it is for and what else it needs is
+/// up to [`crate::methods::Registry`]. A field given twice makes the request
+/// invalid JSON.
+pub fn parse(line: &str) -> Result<Request, Malformed> {
+    let fields: Fields =
+        serde_json::from_str(line).map_err(|e| Malformed::InvalidJson(e.to_string()))?;
+    let method = fields
+        .get("method")
+        .ok_or(Malformed::MissingField("method"))?;
+    let method: String =
+        serde_json::from_str(method.get()).map_err(|_| Malformed::InvalidMethod)?;
+    Ok(Request { method, fields })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_requests() {
+        let request = parse(r#"{"method":"isPrime","number":7.5,"extra":[1]}"#).unwrap();
+        assert_eq!(request.method, "isPrime");
+        assert_eq!(
+            request.fields.number("number"),
+            Ok(Number::Fractional("7.5".to_string()))
+        );
+        assert_eq!(request.fields.optional_number("rounds"), Ok(None));
+        let request = parse(r#" {"number":-1e3, "method":"nextPrime"} "#).unwrap();
+        assert_eq!(request.method, "nextPrime");
+        assert_eq!(request.fields.get("number").unwrap().get(), "-1e3");
+    }
+
+    #[test]
+    fn malformed_requests() {
+        let cases = [
+            ("", "invalid JSON"),
+            ("{", "invalid JSON"),
+            ("[7]", "invalid JSON"),
+            (r#"{"method":"isPrime","number":7"#, "invalid JSON"),
+            (
+                r#"{"method":"isPrime","method":"isPrime","number":7}"#,
+                "invalid JSON",
+            ),
+            (r#"{"number":7}"#, "missing field `method`"),
+            (r#"{"method":1,"number":7}"#, "method is not a string"),
+        ];
+        for (line, reason) in cases {
+            let e = parse(line).unwrap_err();
+            assert!(e.to_string().starts_with(reason), "{line}: {e}");
+        }
+    }
+
+    #[test]
+    fn error_responses() {
+        assert_eq!(
+            Malformed::MissingField("number").response(),
+            r#"{"error":{"code":"missing_field","message":"missing field `number`"}}"#
+        );
+        assert_eq!(
+            Malformed::UnknownMethod("isprime".to_string()).response(),
+            r#"{"error":{"code":"unknown_method","message":"unknown method \"isprime\""}}"#
+        );
+        assert_eq!(
+            Malformed::InvalidParams("`number` is not an integer".to_string()).response(),
+            r#"{"error":{"code":"invalid_params","message":"invalid params: `number` is not an integer"}}"#
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::{Framed, LinesCodec};
+
+use crate::state::State;
+
+/// Answers a single admin command with a JSON document.
+///
+/// Commands are `cameras`, `dispatchers`, `pending`, `tickets <plate>`,
+/// `observations <plate>`, `quarantine` and `stats`.
+pub async fn query(state: &State, command: &str) -> Value {
+    let mut words = command.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("cameras"), None, _) => {
+            let roads: Vec<_> = state
+                .roads
+                .cameras()
+                .into_iter()
+                .map(|(road, (limit, miles))| json!({"road": road, "limit": limit, "miles": miles}))
+                .collect();
+            json!({ "cameras": roads })
+        }
+        (Some("dispatchers"), None, _) => {
+            let roads: Vec<_> = state
+                .ticket_state
+                .lock()
+                .unwrap()
+                .dispatchers()
+                .into_iter()
+                .map(|(road, dispatchers)| json!({"road": road, "dispatchers": dispatchers}))
+                .collect();
+            json!({ "dispatchers": roads })
+        }
+        (Some("pending"), None, _) => {
+            let ticket_state = state.ticket_state.lock().unwrap();
+            let roads: Vec<_> = ticket_state
+                .pending()
+                .into_iter()
+                .map(|(road, tickets)| json!({"road": road, "tickets": tickets}))
+                .collect();
+            json!({ "pending": roads })
+        }
+        (Some("tickets"), Some(plate), None) => {
+            let ticket_state = state.ticket_state.lock().unwrap();
+            json!({ "tickets": ticket_state.issued(plate) })
+        }
+        (Some("observations"), Some(plate), None) => {
+            let observations: Vec<_> = state
+                .positions
+                .history(plate)
+                .into_iter()
+                .map(|(road, position)| {
+                    json!({"road": road, "mile": position.mile, "timestamp": position.timestamp})
+                })
+                .collect();
+            json!({ "observations": observations })
+        }
+        (Some("quarantine"), None, _) => {
+            json!({ "quarantine": state.invalid_plates.quarantined() })
+        }
+        (Some("stats"), None, _) => {
+            let evictions = &state.positions.evictions;
+            let dropped = state.ticket_state.lock().unwrap().dropped;
+            json!({
+                "readings": state.positions.count(),
+                "evicted": {
+                    "expired": evictions.expired.load(Ordering::Relaxed),
+                    "idle": evictions.idle.load(Ordering::Relaxed),
+                    "capacity": evictions.capacity.load(Ordering::Relaxed),
+                },
+                "dropped_tickets": dropped,
+                "invalid_plates": state.invalid_plates.count(),
+            })
+        }
+        _ => json!({ "error": format!("unknown command: {command}") }),
+    }
+}
+
+async fn handle_admin(stream: TcpStream, state: Arc<State>) -> Result<()> {
+    let mut framed = Framed::new(stream, LinesCodec::new_with_max_length(1024));
+    while let Some(line) = framed.next().await {
+        let reply = query(&state, line?.trim()).await;
+        framed.send(reply.to_string()).await?;
+    }
+    Ok(())
+}
+
+/// Serves the admin line protocol: one command per line, one JSON reply per
+/// line.
+pub async fn serve_admin(listener: TcpListener, state: Arc<State>) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_admin(stream, state).await {
+                println!("an admin error occured; error = {:?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::positions::Position;
+    use crate::state::Ticket;
+    use crate::testing::ticket;
+
+    #[tokio::test]
+    async fn query_state() {
+        let state = State::default();
+        state.roads.add_camera(66, 8, 60).unwrap();
+        state.positions.insert(
+            "UN1X",
+            66,
+            Position {
+                timestamp: 0,
+                mile: 8,
+            },
+        );
+        let ticket = ticket(66);
+        {
+            let mut ticket_state = state.ticket_state.lock().unwrap();
+            ticket_state.issue(ticket.clone());
+            ticket_state.issue(Ticket { road: 67, ..ticket });
+            let (sender, _receiver) = mpsc::channel(1);
+            ticket_state.add_dispatcher(&[66], sender);
+        }
+
+        assert_eq!(
+            query(&state, "cameras").await,
+            json!({"cameras": [{"road": 66, "limit": 60, "miles": [8]}]})
+        );
+        assert_eq!(
+            query(&state, "dispatchers").await,
+            json!({"dispatchers": [{"road": 66, "dispatchers": [0]}]})
+        );
+        assert_eq!(query(&state, "pending").await["pending"][0]["road"], 67);
+        assert_eq!(
+            query(&state, "tickets UN1X").await["tickets"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            query(&state, "observations UN1X").await,
+            json!({"observations": [{"road": 66, "mile": 8, "timestamp": 0}]})
+        );
+        assert_eq!(query(&state, "stats").await["readings"], 1);
+        assert!(query(&state, "tickets").await.get("error").is_some());
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::jsonl::{self, Appender, LineFile};
+use crate::state::{DispatcherId, Ticket};
+
+/// A single line of the audit log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    Issued {
+        #[serde(flatten)]
+        ticket: Ticket,
+        // Seconds since the Unix epoch
+        issued_at: u64,
+    },
+    Delivered {
+        #[serde(flatten)]
+        ticket: Ticket,
+        dispatcher: DispatcherId,
+        delivered_at: u64,
+    },
+}
+
+/// Append-only trail of every ticket issued and of the dispatcher that
+/// received it.
+///
+/// Unlike the store, the audit log is never compacted, so it keeps tickets
+/// long after they were delivered.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    events: Appender<AuditEvent>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+impl AuditLog {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            events: Appender::spawn("audit log", LineFile::new(file)),
+        })
+    }
+
+    pub fn issued(&self, ticket: &Ticket) {
+        self.events.append(AuditEvent::Issued {
+            ticket: ticket.clone(),
+            issued_at: now(),
+        });
+    }
+
+    pub fn delivered(&self, ticket: &Ticket, dispatcher: DispatcherId) {
+        self.events.append(AuditEvent::Delivered {
+            ticket: ticket.clone(),
+            dispatcher,
+            delivered_at: now(),
+        });
+    }
+}
+
+/// An issued ticket together with its delivery, if it has been delivered.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditedTicket {
+    #[serde(flatten)]
+    pub ticket: Ticket,
+    pub issued_at: u64,
+    pub dispatcher: Option<DispatcherId>,
+    pub delivered_at: Option<u64>,
+}
+
+/// Reads the audit log at `path` and pairs every issued ticket with its
+/// delivery, in the order the tickets were issued. Lines that can't be read
+/// are reported and skipped.
+pub fn read(path: impl AsRef<Path>) -> io::Result<Vec<AuditedTicket>> {
+    let mut tickets: Vec<AuditedTicket> = vec![];
+    // Ticket -> Indexes of its undelivered copies, oldest first
+    let mut undelivered: HashMap<Ticket, VecDeque<usize>> = HashMap::new();
+    for event in jsonl::read(path)? {
+        match event?.1 {
+            AuditEvent::Issued { ticket, issued_at } => {
+                undelivered
+                    .entry(ticket.clone())
+                    .or_default()
+                    .push_back(tickets.len());
+                tickets.push(AuditedTicket {
+                    ticket,
+                    issued_at,
+                    dispatcher: None,
+                    delivered_at: None,
+                });
+            }
+            AuditEvent::Delivered {
+                ticket,
+                dispatcher,
+                delivered_at,
+            } => {
+                if let Some(i) = undelivered.get_mut(&ticket).and_then(VecDeque::pop_front) {
+                    tickets[i].dispatcher = Some(dispatcher);
+                    tickets[i].delivered_at = Some(delivered_at);
+                }
+            }
+        }
+    }
+    Ok(tickets)
+}
+
+/// Writes `tickets` as CSV with a header line. Speeds are in miles per hour
+/// and undelivered tickets leave the delivery columns empty.
+pub fn write_csv(mut w: impl Write, tickets: &[AuditedTicket]) -> io::Result<()> {
+    writeln!(
+        w,
+        "plate,road,mile1,timestamp1,mile2,timestamp2,speed,issued_at,dispatcher,delivered_at"
+    )?;
+    for audited in tickets {
+        let ticket = &audited.ticket;
+        // Plates are plain alphanumerics, but quote anything that could break
+        // the row.
+        let plate = if ticket.plate.contains([',', '"', '\n']) {
+            format!("\"{}\"", ticket.plate.replace('"', "\"\""))
+        } else {
+            ticket.plate.clone()
+        };
+        writeln!(
+            w,
+            "{},{},{},{},{},{},{:.2},{},{},{}",
+            plate,
+            ticket.road,
+            ticket.mile1,
+            ticket.timestamp1,
+            ticket.mile2,
+            ticket.timestamp2,
+            ticket.speed as f64 / 100.0,
+            audited.issued_at,
+            audited
+                .dispatcher
+                .map_or(String::new(), |id| id.to_string()),
+            audited
+                .delivered_at
+                .map_or(String::new(), |at| at.to_string()),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{temp_path, ticket};
+
+    #[test]
+    fn pair_deliveries_with_issued_tickets() {
+        let path = temp_path("audit");
+        let audit = AuditLog::open(&path).unwrap();
+        audit.issued(&ticket(66));
+        audit.issued(&ticket(67));
+        audit.delivered(&ticket(66), 3);
+        drop(audit);
+
+        let tickets = read(&path).unwrap();
+        assert_eq!(tickets.len(), 2);
+        assert_eq!(tickets[0].dispatcher, Some(3));
+        assert!(tickets[0].delivered_at.is_some());
+        assert_eq!(tickets[1].dispatcher, None);
+
+        let mut csv = vec![];
+        write_csv(&mut csv, &tickets[1..]).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let row = csv.lines().nth(1).unwrap();
+        assert_eq!(
+            row,
+            format!("UN1X,67,8,0,9,45,80.00,{},,", tickets[1].issued_at)
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn skip_torn_lines() {
+        let path = temp_path("torn");
+        let audit = AuditLog::open(&path).unwrap();
+        audit.issued(&ticket(66));
+        drop(audit);
+        // A crash mid-write, after which the daemon carried on.
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"event\":\"iss\n").unwrap();
+        let audit = AuditLog::open(&path).unwrap();
+        audit.issued(&ticket(67));
+        audit.delivered(&ticket(66), 3);
+        drop(audit);
+
+        let tickets = read(&path).unwrap();
+        assert_eq!(tickets.len(), 2);
+        assert_eq!(tickets[0].dispatcher, Some(3));
+        assert_eq!(tickets[1].ticket.road, 67);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,45 @@
+use std::fmt;
+
+use crate::state::Ticket;
+use crate::store::Observation;
+
+/// Where readings and ticketed days are shared.
+///
+/// Every node keeps its readings and ticketed days in memory, in
+/// [`crate::state::State`]. A backend decides what else sees them: nothing
+/// for a single node, or the other nodes of a cluster. Its methods are
+/// called with state locks held, so they must not block.
+pub trait Backend: fmt::Debug + Send + Sync {
+    /// A camera of this node reported a new reading on a road with `limit`.
+    fn observed(&self, _observation: &Observation, _limit: u16) {}
+
+    /// This node issued a ticket for `plate` covering `first..=last`.
+    fn ticketed_days(&self, _plate: &str, _first: u32, _last: u32) {}
+
+    /// The roads this node has dispatchers for changed.
+    fn dispatchers_changed(&self, _roads: &[u16]) {}
+
+    /// Whether this node checks the readings of `plate` for speeding, so a
+    /// car is only ticketed once however many nodes saw it.
+    fn owns(&self, _plate: &str) -> bool {
+        true
+    }
+
+    /// Passes a ticket no dispatcher of this node can take to a node that
+    /// has a dispatcher for its road, or gives it back if there is none.
+    fn hand_over(&self, ticket: Ticket) -> Result<(), Ticket> {
+        Err(ticket)
+    }
+}
+
+/// A single node, keeping everything to itself.
+#[derive(Debug, Default)]
+pub struct InMemory;
+
+impl Backend for InMemory {}
+
+impl Default for Box<dyn Backend> {
+    fn default() -> Self {
+        Box::new(InMemory)
+    }
+}
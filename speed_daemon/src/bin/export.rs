@@ -0,0 +1,50 @@
+//! Exports tickets from a speed daemon audit log.
+//!
+//! ```text
+//! export <audit.jsonl> [--from UNIX_SECS] [--to UNIX_SECS] [--format csv|json]
+//! ```
+//!
+//! Only tickets issued in `[from, to)` are written, as CSV by default.
+
+use std::io::{self, Write};
+
+use anyhow::Result;
+use speed_daemon::audit;
+use speed_daemon::cli;
+
+fn main() -> Result<()> {
+    let mut args = cli::Args::new(std::env::args().skip(1));
+    let mut path = None;
+    let mut from = 0;
+    let mut to = u64::MAX;
+    let mut json = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--from" => from = args.parse(&arg)?,
+            "--to" => to = args.parse(&arg)?,
+            "--format" => match args.value(&arg)?.as_str() {
+                "csv" => json = false,
+                "json" => json = true,
+                other => return Err(anyhow::Error::msg(format!("unknown format: {other}"))),
+            },
+            other if path.is_none() && !other.starts_with("--") => path = Some(other.to_string()),
+            other => return Err(anyhow::Error::msg(format!("unknown argument: {other}"))),
+        }
+    }
+    let Some(path) = path else {
+        return Err(anyhow::Error::msg("usage: export <audit.jsonl> [options]"));
+    };
+
+    let tickets: Vec<_> = audit::read(path)?
+        .into_iter()
+        .filter(|audited| (from..to).contains(&audited.issued_at))
+        .collect();
+    let mut stdout = io::stdout().lock();
+    if json {
+        serde_json::to_writer_pretty(&mut stdout, &tickets)?;
+        writeln!(stdout)?;
+    } else {
+        audit::write_csv(&mut stdout, &tickets)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,203 @@
+//! Replays a recording made with `speed_daemon --record` against an
+//! in-process daemon and compares its tickets with the recorded ones.
+//!
+//! ```text
+//! replay <recording.jsonl> [--speed FACTOR] [--settle SECS] [daemon options]
+//! ```
+//!
+//! Every connection sends its recorded bytes at the recorded times, divided
+//! by `--speed`, which shortens the reorder window to match. Any other
+//! options configure the daemon as they would `speed_daemon`, so a recording
+//! can be replayed with the settings it was made with or with different ones.
+//! The store, audit log, recording and peers are never used.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use bytes::BytesMut;
+use futures::StreamExt;
+use speed_daemon::backend::InMemory;
+use speed_daemon::cli;
+use speed_daemon::codec::ClientCodec;
+use speed_daemon::config::Config;
+use speed_daemon::record::{self, Session};
+use speed_daemon::server::handle;
+use speed_daemon::state::{State, Ticket};
+use speed_daemon::store::Snapshot;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::time::{sleep_until, Instant};
+use tokio_util::codec::{Decoder, FramedRead};
+
+struct Args {
+    recording: String,
+    speed: f64,
+    // Extra time tickets get after the last bytes were sent
+    settle: Option<Duration>,
+    config: Config,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut args = cli::Args::new(std::env::args().skip(1));
+    let mut recording = None;
+    let mut speed: f64 = 1.0;
+    let mut settle = None;
+    let mut rest = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--speed" => speed = args.parse(&arg)?,
+            "--settle" => settle = Some(Duration::try_from_secs_f64(args.parse(&arg)?)?),
+            other if recording.is_none() && !other.starts_with("--") => {
+                recording = Some(other.to_string())
+            }
+            _ => {
+                let value = args.value(&arg)?;
+                rest.extend([arg, value]);
+            }
+        }
+    }
+    let Some(recording) = recording else {
+        return Err(anyhow::Error::msg(
+            "usage: replay <recording.jsonl> [options]",
+        ));
+    };
+    if !(speed > 0.0 && speed.is_finite()) {
+        return Err(anyhow::Error::msg(format!("invalid speed: {speed}")));
+    }
+    let mut config = Config::from_args(rest)?;
+    config.store_dir = None;
+    config.audit_file = None;
+    config.record_file = None;
+    config.admin_addr = None;
+    config.gossip_addr = None;
+    config.peers.clear();
+    // Time passes faster for the daemon as well, or plates would still be
+    // settling when the recorded dispatchers disconnect.
+    config.reorder_window = config.reorder_window.div_f64(speed);
+    Ok(Args {
+        recording,
+        speed,
+        settle,
+        config,
+    })
+}
+
+/// The tickets the daemon sent on a recorded connection.
+fn recorded_tickets(session: &Session) -> Result<Vec<Ticket>> {
+    let mut codec = ClientCodec::new();
+    let mut buf = BytesMut::from(&session.outbound[..]);
+    let mut tickets = vec![];
+    while let Some(message) = codec.decode(&mut buf)? {
+        tickets.extend(Ticket::try_from(message));
+    }
+    Ok(tickets)
+}
+
+/// Sends the client side of `session` to the daemon, with times relative to
+/// `start`, and passes on every ticket it gets back.
+async fn replay(
+    session: Session,
+    state: Arc<State>,
+    start: Instant,
+    speed: f64,
+    tickets: Sender<Ticket>,
+) -> Result<()> {
+    let at = |millis: u64| start + Duration::from_millis(millis).div_f64(speed);
+    sleep_until(at(session.opened)).await;
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move {
+        if let Err(e) = handle(server, state).await {
+            println!("connection {} ended; error = {:?}", session.conn, e);
+        }
+    });
+    let (reader, mut writer) = tokio::io::split(client);
+    tokio::spawn(async move {
+        let mut messages = FramedRead::new(reader, ClientCodec::new());
+        while let Some(Ok(message)) = messages.next().await {
+            if let Ok(ticket) = Ticket::try_from(message) {
+                if tickets.send(ticket).await.is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    for (millis, bytes) in session.inbound {
+        sleep_until(at(millis)).await;
+        writer.write_all(&bytes).await?;
+    }
+    // Connections still open when the recording stopped stay open, so
+    // dispatchers keep receiving tickets.
+    match session.closed {
+        Some(millis) => {
+            sleep_until(at(millis)).await;
+            writer.shutdown().await?;
+        }
+        None => std::future::pending().await,
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = parse_args()?;
+    let sessions = record::read_sessions(&args.recording)?;
+    let mut expected = vec![];
+    for session in &sessions {
+        expected.extend(recorded_tickets(session)?);
+    }
+    let end = sessions
+        .iter()
+        .flat_map(|session| {
+            let last = session.inbound.last().map(|&(millis, _)| millis);
+            [Some(session.opened), last, session.closed]
+        })
+        .flatten()
+        .max()
+        .unwrap_or(0);
+    println!(
+        "replaying {} connections with {} recorded tickets",
+        sessions.len(),
+        expected.len()
+    );
+
+    let settle = args
+        .settle
+        .unwrap_or(args.config.reorder_window + Duration::from_secs(1));
+    let state = Arc::new(State::restore(
+        Snapshot::default(),
+        None,
+        None,
+        Box::new(InMemory),
+        args.config,
+    ));
+    let (sender, mut receiver) = mpsc::channel(1024);
+    let start = Instant::now();
+    for session in sessions {
+        let conn = session.conn;
+        let replay = replay(session, state.clone(), start, args.speed, sender.clone());
+        tokio::spawn(async move {
+            if let Err(e) = replay.await {
+                println!("failed to replay connection {conn}; error = {:?}", e);
+            }
+        });
+    }
+    drop(sender);
+
+    let deadline = start + Duration::from_millis(end).div_f64(args.speed) + settle;
+    let mut received = vec![];
+    loop {
+        tokio::select! {
+            ticket = receiver.recv() => match ticket {
+                Some(ticket) => received.push(ticket),
+                None => break,
+            },
+            _ = sleep_until(deadline) => break,
+        }
+    }
+    println!("received {} tickets", received.len());
+
+    cli::compare_tickets(expected, received)
+}
@@ -0,0 +1,415 @@
+//! Drives a running speed daemon with simulated cameras and dispatchers.
+//!
+//! ```text
+//! simulator <scenario.json> [--addr 127.0.0.1:8000[,ADDR...]] [--cars N] [--seed N]
+//!           [--rate PLATES_PER_SEC] [--round-gap SECS] [--tolerance MPH] [--timeout SECS]
+//! ```
+//!
+//! Every car in the scenario drives past the cameras of its road, one speed
+//! per stretch between two cameras. The simulator sends the resulting plates,
+//! works out which tickets the daemon should issue and compares them with
+//! what its dispatchers receive.
+//!
+//! Each camera has its own connection, so the daemon may receive plates from
+//! different cameras in another order than they were sent. Plates are
+//! therefore sent in rounds, the first reading of every car, then the second
+//! and so on, with `--round-gap` (0.05s by default) between rounds for the
+//! daemon to take each round in. That way every plate arrives in timestamp
+//! order even with no reorder window. A gap of 0 sends everything at once,
+//! which needs a reorder window of a second or so.
+//!
+//! Given several addresses, as for the nodes of a cluster, cameras and
+//! dispatchers are spread over them in turn.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use speed_daemon::cli;
+use speed_daemon::codec::{ClientCodec, ClientToServerMessage};
+use speed_daemon::positions::{speed, Position};
+use speed_daemon::state::{day_span, Ticket, TicketedDays};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tokio_util::codec::Framed;
+
+#[derive(Debug, Deserialize)]
+struct Scenario {
+    roads: Vec<Road>,
+    // Roads of every dispatcher
+    #[serde(default)]
+    dispatchers: Vec<Vec<u16>>,
+    #[serde(default)]
+    cars: Vec<Car>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Road {
+    road: u16,
+    limit: u16,
+    // Mile of every camera
+    cameras: Vec<u16>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Car {
+    plate: String,
+    road: u16,
+    // Timestamp at the first camera
+    start: u32,
+    // Speed in mph between each pair of consecutive cameras
+    speeds: Vec<f64>,
+    // Drive from the highest mile to the lowest
+    #[serde(default)]
+    reverse: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Observation {
+    plate: String,
+    road: u16,
+    position: Position,
+}
+
+struct Args {
+    scenario: String,
+    addrs: Vec<String>,
+    cars: usize,
+    seed: u64,
+    rate: Option<f64>,
+    round_gap: Duration,
+    tolerance: u32,
+    timeout: Duration,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut args = cli::Args::new(std::env::args().skip(1));
+    let mut parsed = Args {
+        scenario: String::new(),
+        addrs: vec!["127.0.0.1:8000".to_string()],
+        cars: 0,
+        seed: 1,
+        rate: None,
+        round_gap: Duration::from_millis(50),
+        tolerance: 50,
+        timeout: Duration::from_secs(10),
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--addr" => parsed.addrs = args.value(&arg)?.split(',').map(str::to_owned).collect(),
+            "--cars" => parsed.cars = args.parse(&arg)?,
+            "--seed" => parsed.seed = args.parse(&arg)?,
+            "--rate" => parsed.rate = Some(args.parse(&arg)?),
+            "--round-gap" => parsed.round_gap = Duration::try_from_secs_f64(args.parse(&arg)?)?,
+            "--tolerance" => parsed.tolerance = (args.parse::<f64>(&arg)? * 100.0).round() as u32,
+            "--timeout" => parsed.timeout = Duration::from_secs(args.parse(&arg)?),
+            other if parsed.scenario.is_empty() && !other.starts_with("--") => {
+                parsed.scenario = other.to_string()
+            }
+            other => return Err(anyhow::Error::msg(format!("unknown argument: {other}"))),
+        }
+    }
+    if parsed.scenario.is_empty() {
+        return Err(anyhow::Error::msg(
+            "usage: simulator <scenario.json> [options]",
+        ));
+    }
+    Ok(parsed)
+}
+
+/// Small xorshift generator, so runs with the same seed send the same traffic.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n.max(1)
+    }
+}
+
+/// Reads a scenario, making sure every car can be simulated.
+fn load_scenario(path: &str) -> Result<Scenario> {
+    let scenario: Scenario = serde_json::from_slice(&std::fs::read(path)?)?;
+    if scenario.roads.is_empty() {
+        return Err(anyhow::Error::msg("scenario has no roads"));
+    }
+    if let Some(road) = scenario.roads.iter().find(|road| road.limit == 0) {
+        // Random cars drive at around the limit.
+        return Err(anyhow::Error::msg(format!(
+            "road {} needs a limit above 0",
+            road.road
+        )));
+    }
+    for car in &scenario.cars {
+        if let Some(speed) = car
+            .speeds
+            .iter()
+            .find(|speed| **speed <= 0.0 || !speed.is_finite())
+        {
+            return Err(anyhow::Error::msg(format!(
+                "{} has speed {speed}, which is not above 0",
+                car.plate
+            )));
+        }
+    }
+    Ok(scenario)
+}
+
+/// Adds `count` cars with random start times and speeds around each road's
+/// limit.
+fn random_cars(scenario: &mut Scenario, count: usize, seed: u64) {
+    let mut rng = Rng(seed.max(1));
+    for n in 0..count {
+        let road = &scenario.roads[rng.below(scenario.roads.len() as u64) as usize];
+        let segments = road.cameras.len().saturating_sub(1);
+        let speeds = (0..segments)
+            .map(|_| road.limit as f64 * (70 + rng.below(60)) as f64 / 100.0)
+            .collect();
+        scenario.cars.push(Car {
+            plate: format!("SIM{n}"),
+            road: road.road,
+            start: rng.below(7 * 24 * 60 * 60) as u32,
+            speeds,
+            reverse: rng.below(2) == 1,
+        });
+    }
+}
+
+fn observations(scenario: &Scenario) -> Result<Vec<Observation>> {
+    let mut observations = vec![];
+    for car in &scenario.cars {
+        let road = scenario
+            .roads
+            .iter()
+            .find(|road| road.road == car.road)
+            .ok_or(anyhow::Error::msg(format!(
+                "{} drives on unknown road {}",
+                car.plate, car.road
+            )))?;
+        let mut miles = road.cameras.clone();
+        miles.sort();
+        if car.reverse {
+            miles.reverse();
+        }
+        if car.speeds.len() + 1 < miles.len() {
+            return Err(anyhow::Error::msg(format!(
+                "{} needs a speed per stretch",
+                car.plate
+            )));
+        }
+
+        let mut timestamp = car.start as f64;
+        for (i, &mile) in miles.iter().enumerate() {
+            if i > 0 {
+                let dist = mile.abs_diff(miles[i - 1]) as f64;
+                timestamp += dist / car.speeds[i - 1] * 60.0 * 60.0;
+            }
+            if timestamp.round() > u32::MAX as f64 {
+                return Err(anyhow::Error::msg(format!(
+                    "{} passes mile {mile} after the last timestamp",
+                    car.plate
+                )));
+            }
+            observations.push(Observation {
+                plate: car.plate.clone(),
+                road: car.road,
+                position: Position {
+                    timestamp: timestamp.round() as u32,
+                    mile,
+                },
+            });
+        }
+    }
+    observations.sort_by_key(|observation| observation.position.timestamp);
+    Ok(observations)
+}
+
+/// The tickets the daemon should send to the scenario's dispatchers when it
+/// receives `observations` in order.
+fn expected_tickets(
+    scenario: &Scenario,
+    observations: &[Observation],
+    tolerance: u32,
+) -> Vec<Ticket> {
+    let limits: HashMap<u16, u16> = scenario
+        .roads
+        .iter()
+        .map(|road| (road.road, road.limit))
+        .collect();
+
+    // Every observation after the first one of a plate on a road is compared
+    // with the one before it.
+    let mut last: HashMap<(&str, u16), Position> = HashMap::new();
+    let mut days: HashMap<&str, TicketedDays> = HashMap::new();
+    let mut tickets = vec![];
+    for observation in observations {
+        let key = (observation.plate.as_str(), observation.road);
+        let Some(prev) = last.insert(key, observation.position) else {
+            continue;
+        };
+        let next = observation.position;
+        let Some(speed) = speed(prev, next) else {
+            continue;
+        };
+        if speed < limits[&observation.road] as u32 * 100 + tolerance {
+            continue;
+        }
+        let (first_day, last_day) = day_span(prev.timestamp, next.timestamp);
+        let ticketed = days.entry(&observation.plate).or_default();
+        if ticketed.overlaps(first_day, last_day) {
+            continue;
+        }
+        ticketed.insert(first_day, last_day);
+        if scenario
+            .dispatchers
+            .iter()
+            .any(|roads| roads.contains(&observation.road))
+        {
+            tickets.push(Ticket {
+                plate: observation.plate.clone(),
+                road: observation.road,
+                mile1: prev.mile,
+                timestamp1: prev.timestamp,
+                mile2: next.mile,
+                timestamp2: next.timestamp,
+                speed: speed.min(u16::MAX as u32) as u16,
+            });
+        }
+    }
+    tickets
+}
+
+async fn connect(addr: &str) -> Result<Framed<TcpStream, ClientCodec>> {
+    let stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+    Ok(Framed::new(stream, ClientCodec::new()))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = parse_args()?;
+    let mut scenario = load_scenario(&args.scenario)?;
+    random_cars(&mut scenario, args.cars, args.seed);
+    let observations = observations(&scenario)?;
+    let expected = expected_tickets(&scenario, &observations, args.tolerance);
+
+    let mut addrs = args.addrs.iter().cycle();
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    for roads in &scenario.dispatchers {
+        let mut framed = connect(addrs.next().unwrap()).await?;
+        framed
+            .send(ClientToServerMessage::IAmDispatcher {
+                roads: roads.clone(),
+            })
+            .await?;
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            while let Some(Ok(message)) = framed.next().await {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(sender);
+
+    let mut cameras = HashMap::new();
+    for road in &scenario.roads {
+        for &mile in &road.cameras {
+            let mut framed = connect(addrs.next().unwrap()).await?;
+            framed
+                .send(ClientToServerMessage::IAmCamera {
+                    road: road.road,
+                    mile,
+                    limit: road.limit,
+                })
+                .await?;
+            cameras.insert((road.road, mile), framed);
+        }
+    }
+    println!(
+        "connected {} cameras and {} dispatchers",
+        cameras.len(),
+        scenario.dispatchers.len()
+    );
+
+    // The n-th reading of every plate goes in round n.
+    let mut rounds: Vec<Vec<&Observation>> = vec![];
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    for observation in &observations {
+        let round = seen.entry(&observation.plate).or_default();
+        if *round == rounds.len() {
+            rounds.push(vec![]);
+        }
+        rounds[*round].push(observation);
+        *round += 1;
+    }
+
+    let start = Instant::now();
+    let mut i = 0;
+    for (n, round) in rounds.iter().enumerate() {
+        if n > 0 && !args.round_gap.is_zero() {
+            tokio::time::sleep(args.round_gap).await;
+        }
+        for observation in round {
+            if let Some(rate) = args.rate {
+                let due = start + Duration::from_secs_f64(i as f64 / rate);
+                tokio::time::sleep_until(due.into()).await;
+            }
+            i += 1;
+            let camera = cameras
+                .get_mut(&(observation.road, observation.position.mile))
+                .expect("every observation comes from a scenario camera");
+            camera
+                .send(ClientToServerMessage::Plate {
+                    plate: observation.plate.clone(),
+                    timestamp: observation.position.timestamp,
+                })
+                .await?;
+        }
+    }
+    let sent = start.elapsed();
+    println!(
+        "sent {} plates in {:.2}s ({:.0}/s)",
+        observations.len(),
+        sent.as_secs_f64(),
+        observations.len() as f64 / sent.as_secs_f64().max(f64::EPSILON)
+    );
+
+    // Wait for every expected ticket, then a little longer for extra ones.
+    let mut received = vec![];
+    let deadline = start + args.timeout;
+    loop {
+        let wait = if received.len() >= expected.len() {
+            Duration::from_millis(500)
+        } else {
+            deadline.saturating_duration_since(Instant::now())
+        };
+        match timeout(wait, receiver.recv()).await {
+            Ok(Some(message)) => match Ticket::try_from(message) {
+                Ok(ticket) => received.push(ticket),
+                Err(message) => println!("dispatcher got {message:?}"),
+            },
+            Ok(None) | Err(_) => break,
+        }
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "received {} of {} expected tickets in {:.2}s ({:.0}/s)",
+        received.len(),
+        expected.len(),
+        elapsed.as_secs_f64(),
+        received.len() as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+    );
+
+    cli::compare_tickets(expected, received)
+}
@@ -0,0 +1,90 @@
+use std::str::FromStr;
+
+use anyhow::Result;
+
+use crate::state::Ticket;
+
+/// Command line arguments, read one at a time along with the value each
+/// option takes.
+#[derive(Debug)]
+pub struct Args<I> {
+    args: I,
+}
+
+impl<I: Iterator<Item = String>> Args<I> {
+    pub fn new(args: impl IntoIterator<IntoIter = I>) -> Self {
+        Args {
+            args: args.into_iter(),
+        }
+    }
+
+    /// The value given to option `arg`.
+    pub fn value(&mut self, arg: &str) -> Result<String> {
+        self.args
+            .next()
+            .ok_or(anyhow::Error::msg(format!("{arg} needs a value")))
+    }
+
+    /// The value given to option `arg`, parsed.
+    pub fn parse<T>(&mut self, arg: &str) -> Result<T>
+    where
+        T: FromStr,
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        Ok(self.value(arg)?.parse()?)
+    }
+}
+
+impl<I: Iterator<Item = String>> Iterator for Args<I> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        self.args.next()
+    }
+}
+
+/// Matches the tickets a daemon sent with those a tool expected, reporting
+/// every one missing or unexpected. Fails if there are any.
+pub fn compare_tickets(mut expected: Vec<Ticket>, received: Vec<Ticket>) -> Result<()> {
+    let mut unexpected = vec![];
+    for ticket in received {
+        match expected.iter().position(|other| *other == ticket) {
+            Some(i) => {
+                expected.remove(i);
+            }
+            None => unexpected.push(ticket),
+        }
+    }
+    for ticket in &expected {
+        println!("missing: {ticket:?}");
+    }
+    for ticket in &unexpected {
+        println!("unexpected: {ticket:?}");
+    }
+
+    let mismatches = expected.len() + unexpected.len();
+    if mismatches > 0 {
+        return Err(anyhow::Error::msg(format!(
+            "{mismatches} mismatched tickets"
+        )));
+    }
+    println!("all tickets match");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::ticket;
+
+    #[test]
+    fn compare_ticket_lists() {
+        let tickets = vec![ticket(66), ticket(66), ticket(67)];
+        let mut shuffled = tickets.clone();
+        shuffled.reverse();
+        assert!(compare_tickets(tickets.clone(), shuffled).is_ok());
+
+        let e = compare_tickets(tickets, vec![ticket(66), ticket(68)]).unwrap_err();
+        assert_eq!(e.to_string(), "3 mismatched tickets");
+    }
+}
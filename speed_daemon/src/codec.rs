@@ -1,6 +1,17 @@
+use std::{fmt, io, str};
 
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
 
-#[derive(Debug)]
+pub const ERROR: u8 = 0x10;
+pub const PLATE: u8 = 0x20;
+pub const TICKET: u8 = 0x21;
+pub const WANT_HEARTBEAT: u8 = 0x40;
+pub const HEARTBEAT: u8 = 0x41;
+pub const I_AM_CAMERA: u8 = 0x80;
+pub const I_AM_DISPATCHER: u8 = 0x81;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum ClientToServerMessage {
     // 0x20
     Plate { plate: String, timestamp: u32 },
@@ -12,7 +23,7 @@ pub enum ClientToServerMessage {
     IAmDispatcher { roads: Vec<u16> },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ServerToClientMessage {
     // 0x10
     Error(String),
@@ -30,3 +41,496 @@ pub enum ServerToClientMessage {
     Heartbeat,
 }
 
+/// A [`Decoder`] for messages sent by cameras and dispatchers, and an
+/// [`Encoder`] for the messages the server sends back.
+///
+/// All integers are big-endian and strings are prefixed with a single length
+/// byte, so a frame is only consumed from the buffer once it is complete.
+#[derive(Clone, Debug, Default)]
+pub struct MessageCodec;
+
+impl MessageCodec {
+    pub fn new() -> Self {
+        MessageCodec
+    }
+}
+
+#[derive(Debug)]
+pub enum MessageCodecError {
+    /// The message type byte is not one the other side may send.
+    UnknownMessage(u8),
+    /// A string field was not valid UTF-8.
+    InvalidString,
+    /// An IO error occurred.
+    Io(io::Error),
+}
+
+impl fmt::Display for MessageCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageCodecError::UnknownMessage(id) => {
+                write!(f, "unexpected message with id: {id:#04x}")
+            }
+            MessageCodecError::InvalidString => write!(f, "string is not valid UTF-8"),
+            MessageCodecError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for MessageCodecError {}
+
+impl From<io::Error> for MessageCodecError {
+    fn from(e: io::Error) -> MessageCodecError {
+        MessageCodecError::Io(e)
+    }
+}
+
+/// Reads fields off a borrowed frame without consuming the underlying buffer.
+///
+/// Every getter returns `None` when the frame is still incomplete.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn str(&mut self) -> Option<Result<&'a str, MessageCodecError>> {
+        let len = self.u8()? as usize;
+        let bytes = self.bytes(len)?;
+        Some(str::from_utf8(bytes).map_err(|_| MessageCodecError::InvalidString))
+    }
+
+    /// Like [`Reader::str`], but replaces invalid UTF-8 instead of failing,
+    /// so the rest of the connection can still be read.
+    fn lossy_str(&mut self) -> Option<String> {
+        let len = self.u8()? as usize;
+        let bytes = self.bytes(len)?;
+        Some(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+fn parse_client_message(
+    reader: &mut Reader,
+) -> Result<Option<ClientToServerMessage>, MessageCodecError> {
+    let Some(id) = reader.u8() else {
+        return Ok(None);
+    };
+    let message = match id {
+        PLATE => {
+            // Plates are validated by the server, which decides what to do
+            // with the ones that aren't text.
+            let Some(plate) = reader.lossy_str() else {
+                return Ok(None);
+            };
+            let Some(timestamp) = reader.u32() else {
+                return Ok(None);
+            };
+            ClientToServerMessage::Plate { plate, timestamp }
+        }
+        WANT_HEARTBEAT => {
+            let Some(interval) = reader.u32() else {
+                return Ok(None);
+            };
+            ClientToServerMessage::WantHeartbeat { interval }
+        }
+        I_AM_CAMERA => {
+            let (Some(road), Some(mile), Some(limit)) = (reader.u16(), reader.u16(), reader.u16())
+            else {
+                return Ok(None);
+            };
+            ClientToServerMessage::IAmCamera { road, mile, limit }
+        }
+        I_AM_DISPATCHER => {
+            let Some(numroads) = reader.u8() else {
+                return Ok(None);
+            };
+            let mut roads = Vec::with_capacity(numroads as usize);
+            for _ in 0..numroads {
+                let Some(road) = reader.u16() else {
+                    return Ok(None);
+                };
+                roads.push(road);
+            }
+            ClientToServerMessage::IAmDispatcher { roads }
+        }
+        other => return Err(MessageCodecError::UnknownMessage(other)),
+    };
+    Ok(Some(message))
+}
+
+impl Decoder for MessageCodec {
+    type Item = ClientToServerMessage;
+    type Error = MessageCodecError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let mut reader = Reader::new(buf);
+        let message = parse_client_message(&mut reader)?;
+        if message.is_some() {
+            let consumed = reader.pos;
+            buf.advance(consumed);
+        }
+        Ok(message)
+    }
+}
+
+fn put_str(buf: &mut BytesMut, s: &str) {
+    // Strings on the wire are at most 255 bytes long.
+    let bytes = &s.as_bytes()[..s.len().min(u8::MAX as usize)];
+    buf.put_u8(bytes.len() as u8);
+    buf.put_slice(bytes);
+}
+
+impl Encoder<ServerToClientMessage> for MessageCodec {
+    type Error = MessageCodecError;
+
+    fn encode(
+        &mut self,
+        message: ServerToClientMessage,
+        buf: &mut BytesMut,
+    ) -> Result<(), MessageCodecError> {
+        match message {
+            ServerToClientMessage::Error(msg) => {
+                buf.reserve(2 + msg.len());
+                buf.put_u8(ERROR);
+                put_str(buf, &msg);
+            }
+            ServerToClientMessage::Ticket {
+                plate,
+                road,
+                mile1,
+                timestamp1,
+                mile2,
+                timestamp2,
+                speed,
+            } => {
+                buf.reserve(2 + plate.len() + 16);
+                buf.put_u8(TICKET);
+                put_str(buf, &plate);
+                buf.put_u16(road);
+                buf.put_u16(mile1);
+                buf.put_u32(timestamp1);
+                buf.put_u16(mile2);
+                buf.put_u32(timestamp2);
+                buf.put_u16(speed);
+            }
+            ServerToClientMessage::Heartbeat => {
+                buf.put_u8(HEARTBEAT);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The client side of [`MessageCodec`], for tools that act as cameras or
+/// dispatchers.
+#[derive(Clone, Debug, Default)]
+pub struct ClientCodec;
+
+impl ClientCodec {
+    pub fn new() -> Self {
+        ClientCodec
+    }
+}
+
+fn parse_server_message(
+    reader: &mut Reader,
+) -> Result<Option<ServerToClientMessage>, MessageCodecError> {
+    let Some(id) = reader.u8() else {
+        return Ok(None);
+    };
+    let message = match id {
+        ERROR => {
+            let Some(msg) = reader.str() else {
+                return Ok(None);
+            };
+            ServerToClientMessage::Error(msg?.to_owned())
+        }
+        TICKET => {
+            let Some(plate) = reader.str() else {
+                return Ok(None);
+            };
+            let plate = plate?.to_owned();
+            let (
+                Some(road),
+                Some(mile1),
+                Some(timestamp1),
+                Some(mile2),
+                Some(timestamp2),
+                Some(speed),
+            ) = (
+                reader.u16(),
+                reader.u16(),
+                reader.u32(),
+                reader.u16(),
+                reader.u32(),
+                reader.u16(),
+            )
+            else {
+                return Ok(None);
+            };
+            ServerToClientMessage::Ticket {
+                plate,
+                road,
+                mile1,
+                timestamp1,
+                mile2,
+                timestamp2,
+                speed,
+            }
+        }
+        HEARTBEAT => ServerToClientMessage::Heartbeat,
+        other => return Err(MessageCodecError::UnknownMessage(other)),
+    };
+    Ok(Some(message))
+}
+
+impl Decoder for ClientCodec {
+    type Item = ServerToClientMessage;
+    type Error = MessageCodecError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let mut reader = Reader::new(buf);
+        let message = parse_server_message(&mut reader)?;
+        if message.is_some() {
+            let consumed = reader.pos;
+            buf.advance(consumed);
+        }
+        Ok(message)
+    }
+}
+
+impl Encoder<ClientToServerMessage> for ClientCodec {
+    type Error = MessageCodecError;
+
+    fn encode(
+        &mut self,
+        message: ClientToServerMessage,
+        buf: &mut BytesMut,
+    ) -> Result<(), MessageCodecError> {
+        match message {
+            ClientToServerMessage::Plate { plate, timestamp } => {
+                buf.reserve(6 + plate.len());
+                buf.put_u8(PLATE);
+                put_str(buf, &plate);
+                buf.put_u32(timestamp);
+            }
+            ClientToServerMessage::WantHeartbeat { interval } => {
+                buf.reserve(5);
+                buf.put_u8(WANT_HEARTBEAT);
+                buf.put_u32(interval);
+            }
+            ClientToServerMessage::IAmCamera { road, mile, limit } => {
+                buf.reserve(7);
+                buf.put_u8(I_AM_CAMERA);
+                buf.put_u16(road);
+                buf.put_u16(mile);
+                buf.put_u16(limit);
+            }
+            ClientToServerMessage::IAmDispatcher { roads } => {
+                // At most 255 roads fit in a single message.
+                let roads = &roads[..roads.len().min(u8::MAX as usize)];
+                buf.reserve(2 + 2 * roads.len());
+                buf.put_u8(I_AM_DISPATCHER);
+                buf.put_u8(roads.len() as u8);
+                for road in roads {
+                    buf.put_u16(*road);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode_all(bytes: &[u8]) -> Vec<ClientToServerMessage> {
+        let mut codec = MessageCodec::new();
+        let mut buf = BytesMut::from(bytes);
+        let mut messages = vec![];
+        while let Some(message) = codec.decode(&mut buf).unwrap() {
+            messages.push(message);
+        }
+        assert!(buf.is_empty());
+        messages
+    }
+
+    fn encode(message: ServerToClientMessage) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        MessageCodec::new().encode(message, &mut buf).unwrap();
+        buf.to_vec()
+    }
+
+    #[test]
+    fn decode_messages() {
+        assert_eq!(
+            decode_all(&[
+                0x20, 0x04, b'U', b'N', b'1', b'X', 0x00, 0x00, 0x03, 0xe8, //
+                0x40, 0x00, 0x00, 0x00, 0x0a, //
+                0x80, 0x00, 0x42, 0x00, 0x64, 0x00, 0x3c, //
+                0x81, 0x03, 0x00, 0x42, 0x01, 0x70, 0x13, 0x88,
+            ]),
+            vec![
+                ClientToServerMessage::Plate {
+                    plate: "UN1X".to_string(),
+                    timestamp: 1000
+                },
+                ClientToServerMessage::WantHeartbeat { interval: 10 },
+                ClientToServerMessage::IAmCamera {
+                    road: 66,
+                    mile: 100,
+                    limit: 60
+                },
+                ClientToServerMessage::IAmDispatcher {
+                    roads: vec![66, 368, 5000]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_partial_frame() {
+        let frame = [0x20, 0x04, b'U', b'N', b'1', b'X', 0x00, 0x00, 0x03, 0xe8];
+        let mut codec = MessageCodec::new();
+        let mut buf = BytesMut::new();
+        for byte in &frame[..frame.len() - 1] {
+            buf.put_u8(*byte);
+            assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        }
+        buf.put_u8(frame[frame.len() - 1]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(ClientToServerMessage::Plate {
+                plate: "UN1X".to_string(),
+                timestamp: 1000
+            })
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_unknown_message() {
+        let mut buf = BytesMut::from(&[0x21, 0x00][..]);
+        assert!(matches!(
+            MessageCodec::new().decode(&mut buf),
+            Err(MessageCodecError::UnknownMessage(0x21))
+        ));
+    }
+
+    #[test]
+    fn decode_invalid_plate() {
+        let mut buf = BytesMut::from(&[0x20, 0x01, 0xff, 0x00, 0x00, 0x00, 0x07][..]);
+        assert_eq!(
+            MessageCodec::new().decode(&mut buf).unwrap(),
+            Some(ClientToServerMessage::Plate {
+                plate: "\u{fffd}".to_string(),
+                timestamp: 7
+            })
+        );
+
+        // Tools reading tickets still insist on proper strings.
+        let mut buf = BytesMut::from(&[0x10, 0x01, 0xff][..]);
+        assert!(matches!(
+            ClientCodec::new().decode(&mut buf),
+            Err(MessageCodecError::InvalidString)
+        ));
+    }
+
+    #[test]
+    fn encode_messages() {
+        assert_eq!(
+            encode(ServerToClientMessage::Error("bad".to_string())),
+            vec![0x10, 0x03, b'b', b'a', b'd']
+        );
+        assert_eq!(
+            encode(ServerToClientMessage::Ticket {
+                plate: "UN1X".to_string(),
+                road: 66,
+                mile1: 100,
+                timestamp1: 123456,
+                mile2: 110,
+                timestamp2: 123816,
+                speed: 10000,
+            }),
+            vec![
+                0x21, 0x04, b'U', b'N', b'1', b'X', 0x00, 0x42, 0x00, 0x64, 0x00, 0x01, 0xe2, 0x40,
+                0x00, 0x6e, 0x00, 0x01, 0xe3, 0xa8, 0x27, 0x10,
+            ]
+        );
+        assert_eq!(encode(ServerToClientMessage::Heartbeat), vec![0x41]);
+    }
+
+    #[test]
+    fn client_codec_roundtrip() {
+        let mut client = ClientCodec::new();
+        let mut server = MessageCodec::new();
+        let mut buf = BytesMut::new();
+
+        let sent = vec![
+            ClientToServerMessage::IAmCamera {
+                road: 66,
+                mile: 100,
+                limit: 60,
+            },
+            ClientToServerMessage::Plate {
+                plate: "UN1X".to_string(),
+                timestamp: 1000,
+            },
+            ClientToServerMessage::WantHeartbeat { interval: 10 },
+            ClientToServerMessage::IAmDispatcher {
+                roads: vec![66, 368],
+            },
+        ];
+        for message in sent.clone() {
+            client.encode(message, &mut buf).unwrap();
+        }
+        for message in sent {
+            assert_eq!(server.decode(&mut buf).unwrap(), Some(message));
+        }
+
+        let replies = vec![
+            ServerToClientMessage::Error("bad".to_string()),
+            ServerToClientMessage::Heartbeat,
+            ServerToClientMessage::Ticket {
+                plate: "UN1X".to_string(),
+                road: 66,
+                mile1: 100,
+                timestamp1: 123456,
+                mile2: 110,
+                timestamp2: 123816,
+                speed: 10000,
+            },
+        ];
+        for reply in replies.clone() {
+            server.encode(reply, &mut buf).unwrap();
+        }
+        for reply in replies {
+            assert_eq!(client.decode(&mut buf).unwrap(), Some(reply));
+        }
+        assert!(buf.is_empty());
+    }
+}
@@ -0,0 +1,388 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::cli::Args;
+use crate::plates::{OnInvalidPlate, PlateRules};
+use crate::positions::Retention;
+
+/// What a ticket is based on.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Enforcement {
+    /// The average speed between two consecutive readings.
+    Pairs,
+    /// The highest average speed over any chain of consecutive readings on a
+    /// road that includes the pair being checked.
+    Average,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Address cameras and dispatchers connect to.
+    pub listen_addr: String,
+    /// Directory of the on-disk store, if the daemon should keep its state.
+    pub store_dir: Option<PathBuf>,
+    /// File every issued and delivered ticket is appended to, if any.
+    pub audit_file: Option<PathBuf>,
+    /// Address of the admin interface, which is off unless set.
+    pub admin_addr: Option<String>,
+    /// Address other nodes replicate readings and tickets to, if this node is
+    /// part of a cluster.
+    pub gossip_addr: Option<String>,
+    /// Gossip addresses of the other nodes of the cluster.
+    pub peers: Vec<String>,
+    /// File every connection's traffic is recorded to for later replay, if
+    /// any.
+    pub record_file: Option<PathBuf>,
+    /// How far over the limit a car must be before it is ticketed, in
+    /// hundredths of a mile per hour.
+    pub tolerance: u32,
+    /// How long a pair of readings waits before it is checked for speeding,
+    /// so readings reported out of order can still land in between.
+    pub reorder_window: Duration,
+    pub enforcement: Enforcement,
+    /// Tickets a dispatcher can have queued before the next one goes to
+    /// another dispatcher or waits with the road.
+    pub dispatcher_queue: usize,
+    /// How long a dispatcher may take to accept a ticket before it is
+    /// disconnected and its tickets go to other dispatchers.
+    pub dispatcher_timeout: Duration,
+    /// Tickets a road keeps while no dispatcher takes them. The oldest ones
+    /// are dropped beyond that.
+    pub max_pending_per_road: usize,
+    /// Which readings are kept in memory.
+    pub retention: Retention,
+    pub plates: PlateRules,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen_addr: "0.0.0.0:8000".to_string(),
+            store_dir: None,
+            audit_file: None,
+            admin_addr: None,
+            gossip_addr: None,
+            peers: vec![],
+            record_file: None,
+            // Cars going 0.5 mph or more over the limit must always be ticketed.
+            tolerance: 50,
+            reorder_window: Duration::ZERO,
+            enforcement: Enforcement::Pairs,
+            dispatcher_queue: 1024,
+            dispatcher_timeout: Duration::from_secs(10),
+            max_pending_per_road: 100_000,
+            retention: Retention {
+                window: None,
+                idle_days: None,
+                max_readings: Some(5_000_000),
+            },
+            plates: PlateRules::default(),
+        }
+    }
+}
+
+/// Settings read from a config file, each of which can be left out.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    listen: Option<String>,
+    store: Option<PathBuf>,
+    audit: Option<PathBuf>,
+    admin: Option<String>,
+    gossip: Option<String>,
+    peers: Option<Vec<String>>,
+    record: Option<PathBuf>,
+    // Miles per hour
+    tolerance: Option<f64>,
+    // Seconds
+    reorder_window: Option<f64>,
+    enforcement: Option<Enforcement>,
+    dispatcher_queue: Option<usize>,
+    // Seconds
+    dispatcher_timeout: Option<f64>,
+    max_pending_per_road: Option<usize>,
+    // Seconds
+    retention: Option<u32>,
+    idle_days: Option<u32>,
+    max_readings: Option<usize>,
+    plate_trim: Option<bool>,
+    plate_uppercase: Option<bool>,
+    plate_chars: Option<String>,
+    invalid_plates: Option<OnInvalidPlate>,
+}
+
+/// Converts a speed in miles per hour such as `0.5` into hundredths.
+fn hundredths(mph: f64) -> Result<u32> {
+    if !(0.0..=655.35).contains(&mph) {
+        return Err(anyhow::Error::msg(format!("speed out of range: {mph}")));
+    }
+    Ok((mph * 100.0).round() as u32)
+}
+
+fn parse_on_invalid_plate(s: &str) -> Result<OnInvalidPlate> {
+    match s {
+        "reject" => Ok(OnInvalidPlate::Reject),
+        "quarantine" => Ok(OnInvalidPlate::Quarantine),
+        other => Err(anyhow::Error::msg(format!(
+            "unknown invalid plate rule: {other}"
+        ))),
+    }
+}
+
+fn parse_enforcement(s: &str) -> Result<Enforcement> {
+    match s {
+        "pairs" => Ok(Enforcement::Pairs),
+        "average" => Ok(Enforcement::Average),
+        other => Err(anyhow::Error::msg(format!("unknown enforcement: {other}"))),
+    }
+}
+
+impl Config {
+    /// Builds the configuration from the defaults, then the file given with
+    /// `--config` if any, then the remaining arguments, and validates it.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let args: Vec<String> = args.into_iter().collect();
+        let mut config = Config::default();
+        if let Some(i) = args.iter().position(|arg| arg == "--config") {
+            let path = args
+                .get(i + 1)
+                .ok_or(anyhow::Error::msg("--config needs a value"))?;
+            config.apply_file(path)?;
+        }
+
+        let mut args = Args::new(args);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => {
+                    args.value(&arg)?;
+                }
+                "--listen" => config.listen_addr = args.value(&arg)?,
+                "--store" => config.store_dir = Some(PathBuf::from(args.value(&arg)?)),
+                "--audit" => config.audit_file = Some(PathBuf::from(args.value(&arg)?)),
+                "--admin" => config.admin_addr = Some(args.value(&arg)?),
+                "--gossip" => config.gossip_addr = Some(args.value(&arg)?),
+                "--peers" => {
+                    config.peers = args
+                        .value(&arg)?
+                        .split(',')
+                        .filter(|peer| !peer.is_empty())
+                        .map(str::to_owned)
+                        .collect()
+                }
+                "--record" => config.record_file = Some(PathBuf::from(args.value(&arg)?)),
+                "--tolerance" => config.tolerance = hundredths(args.parse(&arg)?)?,
+                "--reorder-window" => {
+                    config.reorder_window = Duration::try_from_secs_f64(args.parse(&arg)?)?
+                }
+                "--enforcement" => config.enforcement = parse_enforcement(&args.value(&arg)?)?,
+                "--dispatcher-queue" => config.dispatcher_queue = args.parse(&arg)?,
+                "--dispatcher-timeout" => {
+                    config.dispatcher_timeout = Duration::try_from_secs_f64(args.parse(&arg)?)?
+                }
+                "--max-pending-per-road" => config.max_pending_per_road = args.parse(&arg)?,
+                "--retention" => config.retention.window = Some(args.parse(&arg)?),
+                "--idle-days" => config.retention.idle_days = Some(args.parse(&arg)?),
+                "--max-readings" => config.retention.max_readings = Some(args.parse(&arg)?),
+                "--plate-trim" => config.plates.trim = args.parse(&arg)?,
+                "--plate-uppercase" => config.plates.uppercase = args.parse(&arg)?,
+                "--plate-chars" => config.plates.allowed = Some(args.value(&arg)?),
+                "--invalid-plates" => {
+                    config.plates.on_invalid = parse_on_invalid_plate(&args.value(&arg)?)?
+                }
+                other => return Err(anyhow::Error::msg(format!("unknown argument: {other}"))),
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        let file: ConfigFile = toml::from_str(&text)
+            .map_err(|e| anyhow::Error::msg(format!("{}: {e}", path.display())))?;
+
+        if let Some(listen) = file.listen {
+            self.listen_addr = listen;
+        }
+        if let Some(store) = file.store {
+            self.store_dir = Some(store);
+        }
+        if let Some(audit) = file.audit {
+            self.audit_file = Some(audit);
+        }
+        if let Some(admin) = file.admin {
+            self.admin_addr = Some(admin);
+        }
+        if let Some(gossip) = file.gossip {
+            self.gossip_addr = Some(gossip);
+        }
+        if let Some(peers) = file.peers {
+            self.peers = peers;
+        }
+        if let Some(record) = file.record {
+            self.record_file = Some(record);
+        }
+        if let Some(tolerance) = file.tolerance {
+            self.tolerance = hundredths(tolerance)?;
+        }
+        if let Some(window) = file.reorder_window {
+            self.reorder_window = Duration::try_from_secs_f64(window)?;
+        }
+        if let Some(enforcement) = file.enforcement {
+            self.enforcement = enforcement;
+        }
+        if let Some(queue) = file.dispatcher_queue {
+            self.dispatcher_queue = queue;
+        }
+        if let Some(timeout) = file.dispatcher_timeout {
+            self.dispatcher_timeout = Duration::try_from_secs_f64(timeout)?;
+        }
+        if let Some(max) = file.max_pending_per_road {
+            self.max_pending_per_road = max;
+        }
+        if let Some(window) = file.retention {
+            self.retention.window = Some(window);
+        }
+        if let Some(days) = file.idle_days {
+            self.retention.idle_days = Some(days);
+        }
+        if let Some(max) = file.max_readings {
+            self.retention.max_readings = Some(max);
+        }
+        if let Some(trim) = file.plate_trim {
+            self.plates.trim = trim;
+        }
+        if let Some(uppercase) = file.plate_uppercase {
+            self.plates.uppercase = uppercase;
+        }
+        if let Some(chars) = file.plate_chars {
+            self.plates.allowed = Some(chars);
+        }
+        if let Some(on_invalid) = file.invalid_plates {
+            self.plates.on_invalid = on_invalid;
+        }
+        Ok(())
+    }
+
+    /// Rejects settings the daemon could not run with.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |reason: String| Err(anyhow::Error::msg(reason));
+        if self.listen_addr.parse::<SocketAddr>().is_err() {
+            return invalid(format!("invalid listen address: {}", self.listen_addr));
+        }
+        if let Some(addr) = &self.admin_addr {
+            if addr.parse::<SocketAddr>().is_err() {
+                return invalid(format!("invalid admin address: {addr}"));
+            }
+            if *addr == self.listen_addr {
+                return invalid(format!("admin address {addr} is also the listen address"));
+            }
+        }
+        if let Some(addr) = &self.gossip_addr {
+            if addr.parse::<SocketAddr>().is_err() {
+                return invalid(format!("invalid gossip address: {addr}"));
+            }
+            if *addr == self.listen_addr || Some(addr) == self.admin_addr.as_ref() {
+                return invalid(format!("gossip address {addr} is already in use"));
+            }
+        }
+        for peer in &self.peers {
+            if self.gossip_addr.is_none() {
+                return invalid("peers need a gossip address".to_string());
+            }
+            if peer.parse::<SocketAddr>().is_err() {
+                return invalid(format!("invalid peer address: {peer}"));
+            }
+            if Some(peer) == self.gossip_addr.as_ref() {
+                return invalid(format!("{peer} is this node's own gossip address"));
+            }
+        }
+        if let Some(dir) = &self.store_dir {
+            if dir.exists() && !dir.is_dir() {
+                return invalid(format!("store is not a directory: {}", dir.display()));
+            }
+        }
+        if self.dispatcher_queue == 0 {
+            return invalid("dispatcher queue must hold at least one ticket".to_string());
+        }
+        if self.dispatcher_timeout.is_zero() {
+            return invalid("dispatcher timeout must be above zero".to_string());
+        }
+        if self.max_pending_per_road == 0 {
+            return invalid("roads must keep at least one pending ticket".to_string());
+        }
+        if self.retention.window == Some(0) {
+            return invalid("retention must be at least one second".to_string());
+        }
+        if self.retention.idle_days == Some(0) {
+            return invalid("plates must be kept for at least a day".to_string());
+        }
+        if self.retention.max_readings == Some(0) {
+            return invalid("at least one reading must be kept".to_string());
+        }
+        if self.plates.allowed.as_deref() == Some("") {
+            return invalid("plates must be allowed at least one character".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::temp_path;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn arguments_override_config_file() {
+        let path = temp_path("config.toml");
+        std::fs::write(
+            &path,
+            "listen = \"127.0.0.1:9000\"\ntolerance = 1.5\nretention = 3600\nenforcement = \"average\"\n",
+        )
+        .unwrap();
+
+        let config = Config::from_args(args(&[
+            "--tolerance",
+            "0",
+            "--config",
+            path.to_str().unwrap(),
+        ]))
+        .unwrap();
+        assert_eq!(config.listen_addr, "127.0.0.1:9000");
+        assert_eq!(config.tolerance, 0);
+        assert_eq!(config.retention.window, Some(3600));
+        assert_eq!(config.enforcement, Enforcement::Average);
+
+        std::fs::write(&path, "tolerence = 1\n").unwrap();
+        assert!(Config::from_args(args(&["--config", path.to_str().unwrap()])).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reject_invalid_settings() {
+        assert!(Config::from_args(args(&["--listen", "localhost"])).is_err());
+        assert!(Config::from_args(args(&["--dispatcher-queue", "0"])).is_err());
+        assert!(Config::from_args(args(&["--dispatcher-timeout", "0"])).is_err());
+        assert!(Config::from_args(args(&["--tolerance", "-1"])).is_err());
+        assert!(Config::from_args(args(&["--admin", "0.0.0.0:8000"])).is_err());
+        assert!(Config::from_args(args(&["--retention", "60"])).is_ok());
+        assert!(Config::from_args(args(&["--peers", "127.0.0.1:7001"])).is_err());
+        assert!(Config::from_args(args(&[
+            "--gossip",
+            "127.0.0.1:7000",
+            "--peers",
+            "127.0.0.1:7001,127.0.0.1:7000"
+        ]))
+        .is_err());
+    }
+}
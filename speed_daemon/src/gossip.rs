@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::time::sleep;
+use tokio_util::codec::{Framed, FramedRead, LinesCodec};
+
+use crate::backend::Backend;
+use crate::state::Ticket;
+use crate::store::Observation;
+
+// Updates queued for a peer, beyond which new ones are dropped.
+const PEER_QUEUE: usize = 100_000;
+// How long to wait before dialing a peer that could not be reached again.
+const RECONNECT_AFTER: Duration = Duration::from_millis(500);
+
+// Node -> connection it announced on, and roads it has dispatchers for
+type NodeRoads = Arc<Mutex<HashMap<String, (u64, Vec<u16>)>>>;
+
+/// What nodes tell each other, one JSON document per line.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Update {
+    /// A reading, with the limit of its road, which the receiving node may
+    /// have no camera of its own on.
+    Observation {
+        #[serde(flatten)]
+        observation: Observation,
+        limit: u16,
+    },
+    TicketedDays {
+        plate: String,
+        first_day: u32,
+        last_day: u32,
+    },
+    /// A ticket for a dispatcher of the receiving node to deliver.
+    Ticket(Ticket),
+    /// Every road `node` currently has dispatchers for.
+    Dispatchers { node: String, roads: Vec<u16> },
+}
+
+/// 64-bit FNV-1a, which unlike the std hasher is the same on every node.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// The node among `nodes`, sorted, that checks `plate` for speeding.
+pub(crate) fn owner<'a>(nodes: &'a [String], plate: &str) -> &'a str {
+    &nodes[(fnv1a(plate.as_bytes()) % nodes.len() as u64) as usize]
+}
+
+/// The queue of updates for a peer.
+#[derive(Debug)]
+struct Peer {
+    sender: Sender<Update>,
+    // Whether updates are being dropped, so that is only logged once
+    dropping: AtomicBool,
+}
+
+impl Peer {
+    /// Queues `update`, logging when the peer starts and stops dropping
+    /// updates rather than every one dropped.
+    fn try_send(&self, peer: &str, update: Update) -> bool {
+        match self.sender.try_send(update) {
+            Ok(()) => {
+                if self.dropping.load(Ordering::Relaxed)
+                    && self.dropping.swap(false, Ordering::Relaxed)
+                {
+                    println!("peer {peer} is taking updates again");
+                }
+                true
+            }
+            Err(e) => {
+                if !self.dropping.swap(true, Ordering::Relaxed) {
+                    println!("dropping updates for peer {peer}; error = {:?}", e);
+                }
+                false
+            }
+        }
+    }
+}
+
+/// Replicates readings and ticketed days to every peer over TCP.
+///
+/// Each node dials every peer and only sends on the connections it dialed,
+/// so a node's updates reach a peer in the order they were made. Plates are
+/// spread over the nodes by hash, and only the node a plate hashes to checks
+/// it for speeding. That only works if every node is given the same nodes,
+/// and the plates of a node that is down are not ticketed until it is back.
+#[derive(Debug)]
+pub struct Gossip {
+    // Gossip address of this node
+    addr: String,
+    // Gossip address of every node, this one included, sorted
+    nodes: Vec<String>,
+    peers: HashMap<String, Peer>,
+    roads: NodeRoads,
+    // Roads this node has dispatchers for, as last announced
+    announced: Arc<Mutex<Vec<u16>>>,
+}
+
+impl Gossip {
+    /// Accepts peers on `listener`, reachable by the others at `addr`, and
+    /// starts dialing `peers`. Updates from peers come out of the returned
+    /// receiver.
+    pub fn start(
+        listener: TcpListener,
+        addr: String,
+        peers: &[String],
+    ) -> (Gossip, Receiver<Update>) {
+        let roads = Arc::new(Mutex::new(HashMap::new()));
+        let announced = Arc::new(Mutex::new(vec![]));
+        let (updates, received) = mpsc::channel(PEER_QUEUE);
+        tokio::spawn(accept(listener, updates, roads.clone()));
+
+        let mut senders = HashMap::new();
+        for peer in peers {
+            let (sender, receiver) = mpsc::channel(PEER_QUEUE);
+            let queue = Peer {
+                sender,
+                dropping: AtomicBool::new(false),
+            };
+            senders.insert(peer.clone(), queue);
+            let hello = (addr.clone(), announced.clone());
+            tokio::spawn(dial(peer.clone(), receiver, hello));
+        }
+
+        let mut nodes: Vec<String> = peers.to_vec();
+        nodes.push(addr.clone());
+        nodes.sort();
+        let gossip = Gossip {
+            addr,
+            nodes,
+            peers: senders,
+            roads,
+            announced,
+        };
+        (gossip, received)
+    }
+
+    fn broadcast(&self, update: Update) {
+        for (peer, queue) in &self.peers {
+            queue.try_send(peer, update.clone());
+        }
+    }
+}
+
+impl Backend for Gossip {
+    fn observed(&self, observation: &Observation, limit: u16) {
+        self.broadcast(Update::Observation {
+            observation: observation.clone(),
+            limit,
+        });
+    }
+
+    fn ticketed_days(&self, plate: &str, first: u32, last: u32) {
+        self.broadcast(Update::TicketedDays {
+            plate: plate.to_owned(),
+            first_day: first,
+            last_day: last,
+        });
+    }
+
+    fn dispatchers_changed(&self, roads: &[u16]) {
+        *self.announced.lock().unwrap() = roads.to_vec();
+        self.broadcast(Update::Dispatchers {
+            node: self.addr.clone(),
+            roads: roads.to_vec(),
+        });
+    }
+
+    fn owns(&self, plate: &str) -> bool {
+        owner(&self.nodes, plate) == self.addr
+    }
+
+    fn hand_over(&self, ticket: Ticket) -> Result<(), Ticket> {
+        let peer = self
+            .roads
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, (_, roads))| roads.contains(&ticket.road))
+            .map(|(peer, _)| peer.clone());
+        let Some((peer, queue)) = peer.and_then(|peer| self.peers.get_key_value(&peer)) else {
+            return Err(ticket);
+        };
+        // The peer's queue is only full if it is far behind or gone.
+        if queue.try_send(peer, Update::Ticket(ticket.clone())) {
+            Ok(())
+        } else {
+            Err(ticket)
+        }
+    }
+}
+
+async fn accept(listener: TcpListener, updates: Sender<Update>, roads: NodeRoads) {
+    let mut connections: u64 = 0;
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                println!("failed to accept peer; error = {:?}", e);
+                continue;
+            }
+        };
+        let updates = updates.clone();
+        let roads = roads.clone();
+        let connection = connections;
+        connections += 1;
+        tokio::spawn(async move {
+            if let Err(e) = receive(stream, updates, &roads, connection).await {
+                println!("a peer error occured; error = {:?}", e);
+            }
+            // The node is gone, so tickets must not be handed over to it
+            // unless it has announced its roads again on a new connection.
+            roads
+                .lock()
+                .unwrap()
+                .retain(|_, (announced_on, _)| *announced_on != connection);
+        });
+    }
+}
+
+async fn receive(
+    stream: TcpStream,
+    updates: Sender<Update>,
+    roads: &NodeRoads,
+    connection: u64,
+) -> Result<()> {
+    let mut lines = FramedRead::new(stream, LinesCodec::new());
+    while let Some(line) = lines.next().await {
+        let update: Update = serde_json::from_str(&line?)?;
+        if let Update::Dispatchers {
+            node,
+            roads: node_roads,
+        } = &update
+        {
+            roads
+                .lock()
+                .unwrap()
+                .insert(node.clone(), (connection, node_roads.clone()));
+        }
+        updates.send(update).await?;
+    }
+    Ok(())
+}
+
+/// Keeps a connection to `peer` and sends it every queued update, starting
+/// with the roads this node has dispatchers for.
+async fn dial(
+    peer: String,
+    mut queue: Receiver<Update>,
+    (addr, announced): (String, Arc<Mutex<Vec<u16>>>),
+) {
+    let mut unsent: Option<Update> = None;
+    loop {
+        let stream = match TcpStream::connect(&peer).await {
+            Ok(stream) => stream,
+            Err(_) => {
+                sleep(RECONNECT_AFTER).await;
+                continue;
+            }
+        };
+        println!("connected to peer {peer}");
+        let mut framed = Framed::new(stream, LinesCodec::new());
+        let hello = Update::Dispatchers {
+            node: addr.clone(),
+            roads: announced.lock().unwrap().clone(),
+        };
+        let mut first = std::iter::once(hello).chain(unsent.take());
+        loop {
+            let update = match first.next() {
+                Some(update) => update,
+                None => match queue.recv().await {
+                    Some(update) => update,
+                    None => return,
+                },
+            };
+            let line = serde_json::to_string(&update).expect("updates serialize");
+            if let Err(e) = framed.send(line).await {
+                println!("lost peer {peer}; error = {:?}", e);
+                // Retried once the peer is back, dispatchers are announced
+                // afresh anyway.
+                if !matches!(update, Update::Dispatchers { .. }) {
+                    unsent = Some(update);
+                }
+                break;
+            }
+        }
+        sleep(RECONNECT_AFTER).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn updates_as_json() {
+        let update = Update::Observation {
+            observation: Observation {
+                plate: "UN1X".to_string(),
+                road: 66,
+                mile: 8,
+                timestamp: 0,
+            },
+            limit: 60,
+        };
+        let line = serde_json::to_string(&update).unwrap();
+        assert_eq!(
+            line,
+            r#"{"type":"observation","plate":"UN1X","road":66,"mile":8,"timestamp":0,"limit":60}"#
+        );
+        assert_eq!(serde_json::from_str::<Update>(&line).unwrap(), update);
+    }
+
+    async fn announced(roads: &NodeRoads, node: &str) -> Option<Vec<u16>> {
+        // Give the receiving task time to catch up.
+        sleep(Duration::from_millis(50)).await;
+        roads
+            .lock()
+            .unwrap()
+            .get(node)
+            .map(|(_, roads)| roads.clone())
+    }
+
+    #[tokio::test]
+    async fn forget_roads_of_lost_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let roads = NodeRoads::default();
+        let (updates, mut received) = mpsc::channel(PEER_QUEUE);
+        tokio::spawn(async move { while received.recv().await.is_some() {} });
+        tokio::spawn(accept(listener, updates, roads.clone()));
+
+        let hello = |roads: Vec<u16>| {
+            let update = Update::Dispatchers {
+                node: "127.0.0.1:1".to_string(),
+                roads,
+            };
+            serde_json::to_string(&update).unwrap()
+        };
+        let mut old = Framed::new(TcpStream::connect(addr).await.unwrap(), LinesCodec::new());
+        old.send(hello(vec![66])).await.unwrap();
+        assert_eq!(announced(&roads, "127.0.0.1:1").await, Some(vec![66]));
+        drop(old);
+        assert_eq!(announced(&roads, "127.0.0.1:1").await, None);
+
+        // A connection that drops after the node came back on another one
+        // leaves the new roads alone.
+        let mut old = Framed::new(TcpStream::connect(addr).await.unwrap(), LinesCodec::new());
+        old.send(hello(vec![66])).await.unwrap();
+        let mut new = Framed::new(TcpStream::connect(addr).await.unwrap(), LinesCodec::new());
+        new.send(hello(vec![67])).await.unwrap();
+        assert_eq!(announced(&roads, "127.0.0.1:1").await, Some(vec![67]));
+        drop(old);
+        assert_eq!(announced(&roads, "127.0.0.1:1").await, Some(vec![67]));
+    }
+
+    #[tokio::test]
+    async fn plates_have_one_owner() {
+        let mut gossips = vec![];
+        let addrs = ["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"].map(String::from);
+        for addr in &addrs {
+            let peers: Vec<_> = addrs.iter().filter(|a| *a != addr).cloned().collect();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            gossips.push(Gossip::start(listener, addr.clone(), &peers).0);
+        }
+        for n in 0..100 {
+            let plate = format!("SIM{n}");
+            let owners = gossips.iter().filter(|gossip| gossip.owns(&plate)).count();
+            assert_eq!(owners, 1);
+        }
+    }
+}
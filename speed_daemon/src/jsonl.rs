@@ -0,0 +1,174 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Sender, SyncSender, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Writes `value` as a single JSON line.
+pub fn write_line(w: &mut impl Write, value: &impl Serialize) -> io::Result<()> {
+    serde_json::to_writer(&mut *w, value)?;
+    w.write_all(b"\n")
+}
+
+/// Reads a file of JSON lines, giving each value with its line number.
+///
+/// Lines that don't parse, such as one torn by a crash mid-write, are
+/// reported and skipped. The file may well have been appended to after the
+/// crash, so they are not necessarily at the end.
+pub fn read<T: DeserializeOwned>(
+    path: impl AsRef<Path>,
+) -> io::Result<impl Iterator<Item = io::Result<(usize, T)>>> {
+    let path = path.as_ref();
+    let name = path.display().to_string();
+    let lines = BufReader::new(File::open(path)?).lines().enumerate();
+    Ok(lines.filter_map(move |(i, line)| {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e)),
+        };
+        match serde_json::from_str(&line) {
+            Ok(value) => Some(Ok((i + 1, value))),
+            Err(e) => {
+                // Not on stdout, which may be where an export goes.
+                eprintln!("skipping line {} of {name}; error = {e}", i + 1);
+                None
+            }
+        }
+    }))
+}
+
+/// Where an [`Appender`] writes what it is given.
+pub trait Sink<T>: Send + 'static {
+    fn append(&mut self, value: T) -> io::Result<()>;
+
+    /// Called whenever nothing else is queued.
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// A file of JSON lines, one per value.
+#[derive(Debug)]
+pub struct LineFile(BufWriter<File>);
+
+impl LineFile {
+    pub fn new(file: File) -> LineFile {
+        LineFile(BufWriter::new(file))
+    }
+}
+
+impl<T: Serialize> Sink<T> for LineFile {
+    fn append(&mut self, value: T) -> io::Result<()> {
+        write_line(&mut self.0, &value)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+enum Job<T> {
+    Append(T),
+    // Answered once everything queued before it is flushed
+    Sync(SyncSender<()>),
+}
+
+#[derive(Debug)]
+struct Writer<T> {
+    jobs: Option<Sender<Job<T>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<T> Drop for Writer<T> {
+    fn drop(&mut self) {
+        // Lets the thread write what is left and stop.
+        self.jobs.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Hands values to a sink on a thread of its own, so callers never wait on
+/// the disk, and flushes whenever the queue runs dry.
+///
+/// The queue is unbounded: a disk that can't keep up shows as memory use
+/// rather than as stalled connections. Everything queued is written once the
+/// last clone is dropped.
+#[derive(Debug)]
+pub struct Appender<T> {
+    writer: Arc<Writer<T>>,
+}
+
+impl<T> Clone for Appender<T> {
+    fn clone(&self) -> Self {
+        Appender {
+            writer: self.writer.clone(),
+        }
+    }
+}
+
+impl<T: Send + 'static> Appender<T> {
+    /// Starts the thread writing to `sink`. Failed writes are reported with
+    /// `name`.
+    pub fn spawn(name: &'static str, mut sink: impl Sink<T>) -> Appender<T> {
+        let (jobs, queue) = mpsc::channel();
+        let flush = move |sink: &mut dyn Sink<T>| {
+            if let Err(e) = sink.flush() {
+                println!("failed to write {name}; error = {:?}", e);
+            }
+        };
+        let thread = thread::spawn(move || loop {
+            let job = match queue.try_recv() {
+                Ok(job) => job,
+                Err(TryRecvError::Empty) => {
+                    flush(&mut sink);
+                    match queue.recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    }
+                }
+                Err(TryRecvError::Disconnected) => {
+                    flush(&mut sink);
+                    return;
+                }
+            };
+            match job {
+                Job::Append(value) => {
+                    if let Err(e) = sink.append(value) {
+                        println!("failed to write {name}; error = {:?}", e);
+                    }
+                }
+                Job::Sync(done) => {
+                    flush(&mut sink);
+                    let _ = done.send(());
+                }
+            }
+        });
+        Appender {
+            writer: Arc::new(Writer {
+                jobs: Some(jobs),
+                thread: Some(thread),
+            }),
+        }
+    }
+
+    pub fn append(&self, value: T) {
+        if let Some(jobs) = &self.writer.jobs {
+            // Only fails if the thread panicked, which it reported already.
+            let _ = jobs.send(Job::Append(value));
+        }
+    }
+
+    /// Waits until everything appended so far is written and flushed.
+    pub fn sync(&self) {
+        let (done, wait) = mpsc::sync_channel(1);
+        if let Some(jobs) = &self.writer.jobs {
+            if jobs.send(Job::Sync(done)).is_ok() {
+                let _ = wait.recv();
+            }
+        }
+    }
+}
@@ -0,0 +1,16 @@
+pub mod admin;
+pub mod audit;
+pub mod backend;
+pub mod cli;
+pub mod codec;
+pub mod config;
+pub mod gossip;
+pub mod jsonl;
+pub mod plates;
+pub mod positions;
+pub mod record;
+pub mod server;
+pub mod state;
+pub mod store;
+#[cfg(test)]
+mod testing;
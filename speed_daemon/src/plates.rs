@@ -0,0 +1,162 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+// Quarantined plates kept for inspection, the oldest are dropped first.
+const QUARANTINE_SIZE: usize = 1000;
+
+/// What happens to a plate that is invalid even after normalization.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnInvalidPlate {
+    /// Send the camera an `Error` and disconnect it.
+    Reject,
+    /// Set the reading aside, away from ticketing, and carry on.
+    Quarantine,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlateError {
+    Empty,
+    /// The plate contains a character outside the whitelist, or bytes that
+    /// were not valid UTF-8, which show up as U+FFFD.
+    Disallowed(char),
+}
+
+impl fmt::Display for PlateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlateError::Empty => write!(f, "plate is empty"),
+            PlateError::Disallowed(c) => write!(f, "plate contains {c:?}"),
+        }
+    }
+}
+
+impl std::error::Error for PlateError {}
+
+/// How plates are cleaned up before they are used, so `un1x` and ` UN1X`
+/// can be made the same car as `UN1X`.
+///
+/// By default plates are used exactly as cameras send them, and only those
+/// that aren't valid UTF-8 are invalid, rejected as the protocol requires.
+#[derive(Debug, Clone)]
+pub struct PlateRules {
+    pub trim: bool,
+    pub uppercase: bool,
+    /// Every character a normalized plate may contain, if not any. A plate
+    /// must then also not be empty.
+    pub allowed: Option<String>,
+    pub on_invalid: OnInvalidPlate,
+}
+
+impl Default for PlateRules {
+    fn default() -> Self {
+        PlateRules {
+            trim: false,
+            uppercase: false,
+            allowed: None,
+            on_invalid: OnInvalidPlate::Reject,
+        }
+    }
+}
+
+impl PlateRules {
+    pub fn normalize(&self, raw: &str) -> Result<String, PlateError> {
+        if raw.contains(char::REPLACEMENT_CHARACTER) {
+            return Err(PlateError::Disallowed(char::REPLACEMENT_CHARACTER));
+        }
+        let plate = if self.trim { raw.trim() } else { raw };
+        let plate = if self.uppercase {
+            plate.to_uppercase()
+        } else {
+            plate.to_owned()
+        };
+        if let Some(allowed) = &self.allowed {
+            if plate.is_empty() {
+                return Err(PlateError::Empty);
+            }
+            if let Some(c) = plate.chars().find(|c| !allowed.contains(*c)) {
+                return Err(PlateError::Disallowed(c));
+            }
+        }
+        Ok(plate)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QuarantinedPlate {
+    pub plate: String,
+    pub road: u16,
+    pub mile: u16,
+    pub timestamp: u32,
+    pub reason: String,
+}
+
+/// Plates that failed normalization, whether rejected or quarantined.
+#[derive(Debug, Default)]
+pub struct InvalidPlates {
+    count: AtomicU64,
+    quarantine: Mutex<VecDeque<QuarantinedPlate>>,
+}
+
+impl InvalidPlates {
+    pub fn reject(&self) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn quarantine(&self, plate: QuarantinedPlate) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let mut quarantine = self.quarantine.lock().unwrap();
+        if quarantine.len() >= QUARANTINE_SIZE {
+            quarantine.pop_front();
+        }
+        quarantine.push_back(plate);
+    }
+
+    /// Invalid plates seen since startup.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// The most recently quarantined plates, oldest first.
+    pub fn quarantined(&self) -> Vec<QuarantinedPlate> {
+        self.quarantine.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn normalize_plates() {
+        let rules = PlateRules::default();
+        assert_eq!(rules.normalize(" un-1x"), Ok(" un-1x".to_string()));
+        assert_eq!(rules.normalize(""), Ok(String::new()));
+        assert_eq!(
+            rules.normalize("UN\u{fffd}X"),
+            Err(PlateError::Disallowed('\u{fffd}'))
+        );
+
+        let rules = PlateRules {
+            trim: true,
+            uppercase: true,
+            allowed: Some("ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789".to_string()),
+            ..PlateRules::default()
+        };
+        assert_eq!(rules.normalize(" un1x\t"), Ok("UN1X".to_string()));
+        assert_eq!(rules.normalize("  "), Err(PlateError::Empty));
+        assert_eq!(rules.normalize("UN-1X"), Err(PlateError::Disallowed('-')));
+
+        let rules = PlateRules {
+            trim: false,
+            uppercase: false,
+            ..rules
+        };
+        assert_eq!(rules.normalize("un1x"), Err(PlateError::Disallowed('u')));
+        assert_eq!(rules.normalize(" UN1X"), Err(PlateError::Disallowed(' ')));
+    }
+}
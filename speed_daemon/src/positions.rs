@@ -0,0 +1,639 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::state::DAY;
+
+// Roads are spread over this many independently locked maps.
+const SHARDS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub timestamp: u32,
+    pub mile: u16,
+}
+
+/// Average speed between two readings in hundredths of a mile per hour,
+/// rounded to the nearest hundredth, or `None` if both share a timestamp.
+pub fn speed(prev: Position, next: Position) -> Option<u32> {
+    average_speed(&[prev, next])
+}
+
+/// Average speed along a chain of readings sorted by timestamp, in the same
+/// unit as [`speed`], or `None` if any two consecutive readings share a
+/// timestamp.
+pub fn average_speed(readings: &[Position]) -> Option<u32> {
+    let mut dist = 0;
+    for step in readings.windows(2) {
+        if step[0].timestamp == step[1].timestamp {
+            return None;
+        }
+        dist += step[1].mile.abs_diff(step[0].mile) as u64;
+    }
+    let time = readings
+        .last()?
+        .timestamp
+        .abs_diff(readings.first()?.timestamp);
+    speed_over(dist, time)
+}
+
+/// Speed covering `dist` miles in `time` seconds, in the same unit as
+/// [`speed`].
+fn speed_over(dist: u64, time: u32) -> Option<u32> {
+    if time == 0 {
+        return None;
+    }
+    // Even a chain of millions of readings 65535 miles apart fits in a u64.
+    let (dist, time) = (dist * 60 * 60 * 100, time as u64);
+    let speed = (dist + time / 2) / time;
+    Some(speed.min(u32::MAX as u64) as u32)
+}
+
+/// The chain of consecutive `readings` containing both `prev` and `next` with
+/// the highest average speed, as its first and last reading and that speed.
+/// Among equally fast chains the longest wins.
+pub fn fastest_segment(
+    readings: &[Position],
+    prev: Position,
+    next: Position,
+) -> Option<(Position, Position, u32)> {
+    let first = readings.iter().position(|&reading| reading == prev)?;
+    let last = readings.iter().position(|&reading| reading == next)?;
+    let simultaneous = |i: usize| readings[i].timestamp == readings[i + 1].timestamp;
+    if first > last || (first..last).any(simultaneous) {
+        return None;
+    }
+    // A chain can't step between readings that share a timestamp.
+    let earliest = (0..first)
+        .rev()
+        .find(|&i| simultaneous(i))
+        .map_or(0, |i| i + 1);
+    let latest = (last..readings.len() - 1)
+        .find(|&i| simultaneous(i))
+        .unwrap_or(readings.len() - 1);
+
+    // Miles covered from the earliest reading up to each one.
+    let mut dist = Vec::with_capacity(latest - earliest + 1);
+    let mut total = 0;
+    for i in earliest..=latest {
+        if i > earliest {
+            total += readings[i].mile.abs_diff(readings[i - 1].mile) as u64;
+        }
+        dist.push(total);
+    }
+
+    let mut fastest: Option<(usize, usize, u32)> = None;
+    for start in earliest..=first {
+        for end in last..=latest {
+            let time = readings[end].timestamp - readings[start].timestamp;
+            let Some(speed) = speed_over(dist[end - earliest] - dist[start - earliest], time)
+            else {
+                continue;
+            };
+            let faster = fastest.is_none_or(|(other_start, other_end, other)| {
+                (speed, end - start) > (other, other_end - other_start)
+            });
+            if faster {
+                fastest = Some((start, end, speed));
+            }
+        }
+    }
+    fastest.map(|(start, end, speed)| (readings[start], readings[end], speed))
+}
+
+/// Pairs `new` with the readings at the closest earlier and later
+/// timestamps, earliest first in each pair.
+fn neighbours(by_time: &BTreeMap<u32, Vec<u16>>, new: Position) -> Vec<(Position, Position)> {
+    let mut pairs = vec![];
+    if let Some((&timestamp, miles)) = by_time.range(..new.timestamp).next_back() {
+        pairs.extend(
+            miles
+                .iter()
+                .map(|&mile| (Position { timestamp, mile }, new)),
+        );
+    }
+    if let Some((&timestamp, miles)) = by_time
+        .range((Bound::Excluded(new.timestamp), Bound::Unbounded))
+        .next()
+    {
+        pairs.extend(
+            miles
+                .iter()
+                .map(|&mile| (new, Position { timestamp, mile })),
+        );
+    }
+    pairs
+}
+
+#[derive(Debug, Default)]
+struct Readings {
+    // Value of the use counter when this plate last passed a camera here
+    last_used: u64,
+    // Timestamp -> Miles
+    by_time: BTreeMap<u32, Vec<u16>>,
+}
+
+impl Readings {
+    fn len(&self) -> usize {
+        self.by_time.values().map(Vec::len).sum()
+    }
+
+    fn latest(&self) -> Option<u32> {
+        self.by_time
+            .last_key_value()
+            .map(|(&timestamp, _)| timestamp)
+    }
+
+    /// Drops readings from before `cutoff` and returns how many there were.
+    fn forget_before(&mut self, cutoff: u32) -> usize {
+        let kept = self.by_time.split_off(&cutoff);
+        let forgotten = std::mem::replace(&mut self.by_time, kept);
+        forgotten.values().map(Vec::len).sum()
+    }
+}
+
+// (Plate,Road) -> Readings
+type Shard = HashMap<(String, u16), Readings>;
+
+/// Limits on the readings [`Positions`] keeps. Ages are measured against the
+/// latest timestamp any camera reported.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Retention {
+    /// Seconds of readings kept.
+    pub window: Option<u32>,
+    /// Days a plate is remembered on a road after its last reading.
+    pub idle_days: Option<u32>,
+    /// Readings kept in total. Plates seen least recently go first.
+    pub max_readings: Option<usize>,
+}
+
+impl Retention {
+    /// Timestamp before which a plate with no readings left has its ticketed
+    /// days and tickets forgotten, or `None` if they are kept forever.
+    pub fn forget_tickets_before(&self, latest: u32) -> Option<u32> {
+        let expired = self.window.map(|window| latest.saturating_sub(window));
+        let idle = self
+            .idle_days
+            .map(|days| latest.saturating_sub(days.saturating_mul(DAY)));
+        expired.max(idle)
+    }
+}
+
+/// Readings dropped so far, by reason.
+#[derive(Debug, Default)]
+pub struct Evictions {
+    // Older than the retention window
+    pub expired: AtomicU64,
+    // Plate not seen on the road for too long
+    pub idle: AtomicU64,
+    // Over the global cap
+    pub capacity: AtomicU64,
+}
+
+/// Every observation, kept sorted by timestamp for each plate and road.
+///
+/// Observations are sharded by road, so cameras on different roads never wait
+/// on each other.
+///
+/// A car can't be at two miles at once, but two cameras can still report the
+/// same plate with the same timestamp. Such readings are both kept, yet never
+/// paired with each other since there is no speed to derive from them.
+#[derive(Debug)]
+pub struct Positions {
+    shards: Vec<Mutex<Shard>>,
+    retention: Retention,
+    // Latest timestamp reported by any camera
+    latest: AtomicU32,
+    // Bumped on every insert to order plates by when they were last seen
+    uses: AtomicU64,
+    len: AtomicUsize,
+    // Held while evicting, so only one insert at a time does it
+    evicting: Mutex<()>,
+    pub evictions: Evictions,
+}
+
+impl Default for Positions {
+    fn default() -> Self {
+        Positions::new(Retention::default())
+    }
+}
+
+impl Positions {
+    pub fn new(retention: Retention) -> Self {
+        Positions {
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            retention,
+            latest: AtomicU32::new(0),
+            uses: AtomicU64::new(0),
+            len: AtomicUsize::new(0),
+            evicting: Mutex::new(()),
+            evictions: Evictions::default(),
+        }
+    }
+
+    fn shard(&self, road: u16) -> &Mutex<Shard> {
+        &self.shards[road as usize % SHARDS]
+    }
+
+    /// Number of readings kept.
+    pub fn count(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Latest timestamp reported by any camera.
+    pub fn latest(&self) -> u32 {
+        self.latest.load(Ordering::Relaxed)
+    }
+
+    /// Every plate with readings kept on any road.
+    pub fn plates(&self) -> HashSet<String> {
+        let mut plates = HashSet::new();
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            plates.extend(shard.keys().map(|(plate, _)| plate.clone()));
+        }
+        plates
+    }
+
+    /// Records an observation and returns the pairs it forms with the readings
+    /// at the closest earlier and later timestamps, earliest first in each
+    /// pair, or `None` if the exact same reading was already recorded.
+    pub fn insert(
+        &self,
+        plate: &str,
+        road: u16,
+        new: Position,
+    ) -> Option<Vec<(Position, Position)>> {
+        let latest = self
+            .latest
+            .fetch_max(new.timestamp, Ordering::Relaxed)
+            .max(new.timestamp);
+        let last_used = self.uses.fetch_add(1, Ordering::Relaxed) + 1;
+
+        let pairs = {
+            let mut shard = self.shard(road).lock().unwrap();
+            let key = (plate.to_owned(), road);
+            let readings = shard.entry(key.clone()).or_default();
+            let miles = readings.by_time.entry(new.timestamp).or_default();
+            if miles.contains(&new.mile) {
+                return None;
+            }
+            miles.push(new.mile);
+            readings.last_used = last_used;
+            self.len.fetch_add(1, Ordering::Relaxed);
+
+            if let Some(window) = self.retention.window {
+                let expired = readings.forget_before(latest.saturating_sub(window));
+                self.len.fetch_sub(expired, Ordering::Relaxed);
+                self.evictions
+                    .expired
+                    .fetch_add(expired as u64, Ordering::Relaxed);
+            }
+            if readings.by_time.is_empty() {
+                shard.remove(&key);
+                vec![]
+            } else if !readings.by_time.contains_key(&new.timestamp) {
+                vec![]
+            } else {
+                neighbours(&readings.by_time, new)
+            }
+        };
+
+        if self
+            .retention
+            .max_readings
+            .is_some_and(|max| self.count() > max)
+        {
+            self.evict();
+        }
+        Some(pairs)
+    }
+
+    /// Drops the plates seen least recently until a tenth of the cap is free,
+    /// so not every insert past the cap has to go through every shard.
+    fn evict(&self) {
+        let Ok(_evicting) = self.evicting.try_lock() else {
+            return;
+        };
+        let Some(max) = self.retention.max_readings else {
+            return;
+        };
+        let target = max - max / 10;
+
+        let mut candidates = vec![];
+        for (i, shard) in self.shards.iter().enumerate() {
+            let shard = shard.lock().unwrap();
+            candidates.extend(
+                shard
+                    .iter()
+                    .map(|(key, readings)| (readings.last_used, i, key.clone())),
+            );
+        }
+        candidates.sort_unstable_by_key(|(last_used, _, _)| *last_used);
+
+        for (last_used, i, key) in candidates {
+            if self.count() <= target {
+                break;
+            }
+            let mut shard = self.shards[i].lock().unwrap();
+            // Skip plates that passed a camera since the candidates were taken.
+            if shard
+                .get(&key)
+                .is_some_and(|readings| readings.last_used == last_used)
+            {
+                let evicted = shard.remove(&key).unwrap().len();
+                self.len.fetch_sub(evicted, Ordering::Relaxed);
+                self.evictions
+                    .capacity
+                    .fetch_add(evicted as u64, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Drops readings past the retention window and plates that have been
+    /// idle for too long. Meant to run periodically.
+    pub fn sweep(&self) {
+        let latest = self.latest.load(Ordering::Relaxed);
+        let window_cutoff = self
+            .retention
+            .window
+            .map(|window| latest.saturating_sub(window));
+        let idle_cutoff = self
+            .retention
+            .idle_days
+            .map(|days| latest.saturating_sub(days.saturating_mul(DAY)));
+
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            shard.retain(|_, readings| {
+                if let Some(cutoff) = window_cutoff {
+                    let expired = readings.forget_before(cutoff);
+                    self.len.fetch_sub(expired, Ordering::Relaxed);
+                    self.evictions
+                        .expired
+                        .fetch_add(expired as u64, Ordering::Relaxed);
+                }
+                let idle = match (idle_cutoff, readings.latest()) {
+                    (_, None) => return false,
+                    (Some(cutoff), Some(seen)) => seen < cutoff,
+                    (None, Some(_)) => false,
+                };
+                if idle {
+                    let evicted = readings.len();
+                    self.len.fetch_sub(evicted, Ordering::Relaxed);
+                    self.evictions
+                        .idle
+                        .fetch_add(evicted as u64, Ordering::Relaxed);
+                }
+                !idle
+            });
+        }
+    }
+
+    /// The readings of `plate` on `road` that a chain through `prev` and
+    /// `next` can span, sorted by timestamp: those before `prev` and after
+    /// `next` up to the first timestamp with more than one reading, which no
+    /// chain can step across.
+    pub fn chain(&self, plate: &str, road: u16, prev: Position, next: Position) -> Vec<Position> {
+        let shard = self.shard(road).lock().unwrap();
+        let Some(readings) = shard.get(&(plate.to_owned(), road)) else {
+            return vec![];
+        };
+        let single = |(&timestamp, miles): (&u32, &Vec<u16>)| match miles[..] {
+            [mile] => Some(Position { timestamp, mile }),
+            _ => None,
+        };
+        let mut chain: Vec<_> = readings
+            .by_time
+            .range(..prev.timestamp)
+            .rev()
+            .map_while(single)
+            .collect();
+        chain.reverse();
+        chain.extend([prev, next]);
+        chain.extend(
+            readings
+                .by_time
+                .range((Bound::Excluded(next.timestamp), Bound::Unbounded))
+                .map_while(single),
+        );
+        chain
+    }
+
+    /// Whether both readings are recorded with no other reading in between.
+    pub fn adjacent(&self, plate: &str, road: u16, prev: Position, next: Position) -> bool {
+        let shard = self.shard(road).lock().unwrap();
+        let Some(readings) = shard.get(&(plate.to_owned(), road)) else {
+            return false;
+        };
+        let recorded = |position: Position| {
+            readings
+                .by_time
+                .get(&position.timestamp)
+                .is_some_and(|miles| miles.contains(&position.mile))
+        };
+        recorded(prev)
+            && recorded(next)
+            && readings
+                .by_time
+                .range((
+                    Bound::Excluded(prev.timestamp),
+                    Bound::Excluded(next.timestamp),
+                ))
+                .next()
+                .is_none()
+    }
+
+    /// Every observation of `plate` on any road, by road and then by timestamp.
+    pub fn history(&self, plate: &str) -> Vec<(u16, Position)> {
+        let mut history = vec![];
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            for ((other, road), readings) in shard.iter() {
+                if other == plate {
+                    history.extend(readings.by_time.iter().flat_map(|(&timestamp, miles)| {
+                        miles
+                            .iter()
+                            .map(move |&mile| (*road, Position { timestamp, mile }))
+                    }));
+                }
+            }
+        }
+        history.sort_by_key(|(road, position)| (*road, position.timestamp));
+        history
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn position(timestamp: u32, mile: u16) -> Position {
+        Position { timestamp, mile }
+    }
+
+    #[test]
+    fn speed_in_hundredths() {
+        assert_eq!(speed(position(0, 8), position(45, 9)), Some(8000));
+        assert_eq!(speed(position(45, 9), position(0, 8)), Some(8000));
+        assert_eq!(speed(position(0, 0), position(3599, 1)), Some(100));
+        assert_eq!(speed(position(0, 0), position(7, 1)), Some(51429));
+        assert_eq!(
+            speed(position(0, 0), position(3600, u16::MAX)),
+            Some(6553500)
+        );
+        assert_eq!(speed(position(0, 0), position(1, u16::MAX)), Some(u32::MAX));
+        assert_eq!(speed(position(5, 0), position(5, 1)), None);
+    }
+
+    #[test]
+    fn fastest_chain_of_readings() {
+        let readings = [
+            position(0, 0),
+            position(3600, 50),
+            position(5400, 100),
+            position(9000, 160),
+            position(12600, 170),
+        ];
+        assert_eq!(average_speed(&readings[..3]), Some(6667));
+        assert_eq!(average_speed(&[position(0, 0), position(0, 1)]), None);
+
+        // 100 mph from mile 50 to 100 beats any chain through it.
+        assert_eq!(
+            fastest_segment(&readings, readings[1], readings[2]),
+            Some((readings[1], readings[2], 10000))
+        );
+        // At a steady 60 mph the whole chain is reported.
+        let steady = [position(0, 0), position(3600, 60), position(7200, 120)];
+        assert_eq!(
+            fastest_segment(&steady, steady[1], steady[2]),
+            Some((steady[0], steady[2], 6000))
+        );
+        // Chains stop short of readings that share a timestamp.
+        let blocked = [position(0, 0), position(0, 50), position(3600, 60)];
+        assert_eq!(
+            fastest_segment(&blocked, blocked[1], blocked[2]),
+            Some((blocked[1], blocked[2], 1000))
+        );
+        assert_eq!(fastest_segment(&blocked, blocked[0], blocked[2]), None);
+    }
+
+    #[test]
+    fn chain_reads_up_to_simultaneous_readings() {
+        let positions = Positions::default();
+        for reading in [(0, 0), (0, 5), (60, 10), (120, 20), (180, 30), (240, 40)] {
+            positions.insert("UN1X", 66, position(reading.0, reading.1));
+        }
+        positions.insert("UN1X", 66, position(240, 45));
+        assert_eq!(
+            positions.chain("UN1X", 66, position(120, 20), position(180, 30)),
+            [position(60, 10), position(120, 20), position(180, 30)]
+        );
+    }
+
+    #[test]
+    fn pairs_with_neighbours_only() {
+        let positions = Positions::default();
+        assert_eq!(positions.insert("UN1X", 66, position(0, 8)), Some(vec![]));
+        assert_eq!(
+            positions.insert("UN1X", 66, position(100, 10)),
+            Some(vec![(position(0, 8), position(100, 10))])
+        );
+        assert_eq!(
+            positions.insert("UN1X", 66, position(50, 9)),
+            Some(vec![
+                (position(0, 8), position(50, 9)),
+                (position(50, 9), position(100, 10))
+            ])
+        );
+        assert_eq!(
+            positions.insert("UN1X", 66, position(200, 12)),
+            Some(vec![(position(100, 10), position(200, 12))])
+        );
+        assert!(!positions.adjacent("UN1X", 66, position(0, 8), position(100, 10)));
+        assert!(positions.adjacent("UN1X", 66, position(50, 9), position(100, 10)));
+    }
+
+    #[test]
+    fn roads_and_plates_are_separate() {
+        let positions = Positions::default();
+        positions.insert("UN1X", 66, position(0, 8));
+        assert_eq!(positions.insert("UN1X", 67, position(10, 9)), Some(vec![]));
+        assert_eq!(
+            positions.insert("RE05BKG", 66, position(10, 9)),
+            Some(vec![])
+        );
+    }
+
+    #[test]
+    fn forget_readings_past_retention() {
+        let positions = Positions::new(Retention {
+            window: Some(3600),
+            ..Retention::default()
+        });
+        positions.insert("UN1X", 66, position(0, 8));
+        assert_eq!(
+            positions.insert("UN1X", 66, position(4000, 9)),
+            Some(vec![])
+        );
+        assert_eq!(
+            positions.insert("UN1X", 66, position(100, 10)),
+            Some(vec![])
+        );
+        assert_eq!(positions.history("UN1X"), vec![(66, position(4000, 9))]);
+        assert_eq!(positions.evictions.expired.load(Ordering::Relaxed), 2);
+        assert_eq!(positions.count(), 1);
+    }
+
+    #[test]
+    fn sweep_idle_plates() {
+        let positions = Positions::new(Retention {
+            idle_days: Some(1),
+            ..Retention::default()
+        });
+        positions.insert("UN1X", 66, position(0, 8));
+        positions.insert("UN1X", 66, position(60, 9));
+        positions.insert("RE05BKG", 66, position(2 * DAY, 8));
+        positions.sweep();
+        assert_eq!(positions.history("UN1X"), vec![]);
+        assert_eq!(positions.evictions.idle.load(Ordering::Relaxed), 2);
+        assert_eq!(positions.count(), 1);
+    }
+
+    #[test]
+    fn evict_least_recently_seen_plates() {
+        let positions = Positions::new(Retention {
+            max_readings: Some(10),
+            ..Retention::default()
+        });
+        for n in 0..10 {
+            positions.insert(&format!("CAR{n}"), 66, position(0, 8));
+        }
+        positions.insert("CAR0", 66, position(60, 9));
+        // Over the cap, so everything up to CAR2 goes but CAR0 was just seen.
+        assert_eq!(positions.count(), 9);
+        assert_eq!(positions.history("CAR0").len(), 2);
+        assert_eq!(positions.history("CAR1"), vec![]);
+        assert_eq!(positions.history("CAR2"), vec![]);
+        assert_eq!(positions.evictions.capacity.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn duplicate_and_simultaneous_readings() {
+        let positions = Positions::default();
+        positions.insert("UN1X", 66, position(0, 8));
+        assert_eq!(positions.insert("UN1X", 66, position(0, 8)), None);
+
+        // Same instant at another mile: kept, but not paired with mile 8.
+        assert_eq!(positions.insert("UN1X", 66, position(0, 10)), Some(vec![]));
+        assert_eq!(
+            positions.insert("UN1X", 66, position(60, 9)),
+            Some(vec![
+                (position(0, 8), position(60, 9)),
+                (position(0, 10), position(60, 9))
+            ])
+        );
+        assert_eq!(positions.history("UN1X").len(), 3);
+    }
+}
@@ -0,0 +1,257 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::jsonl::{self, Appender, LineFile};
+
+/// A single line of a recording. Times are milliseconds since recording
+/// started and bytes are hex encoded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SessionEvent {
+    Open { conn: u64, at: u64 },
+    // Bytes the client sent
+    In { conn: u64, at: u64, bytes: String },
+    // Bytes the server sent back
+    Out { conn: u64, at: u64, bytes: String },
+    Close { conn: u64, at: u64 },
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Writes every connection's traffic to a file, so a failed run can be
+/// replayed against the daemon later. Connections only queue their events,
+/// the file is written on a thread of its own.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    events: Appender<SessionEvent>,
+    start: Instant,
+    next_conn: Arc<AtomicU64>,
+}
+
+impl Recorder {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Recorder> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Recorder {
+            events: Appender::spawn("recording", LineFile::new(file)),
+            start: Instant::now(),
+            next_conn: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn append(&self, event: SessionEvent) {
+        self.events.append(event);
+    }
+
+    /// Wraps a new connection so everything read from and written to it is
+    /// recorded.
+    pub fn record<S>(&self, stream: S) -> Recording<S> {
+        let conn = self.next_conn.fetch_add(1, Ordering::Relaxed);
+        self.append(SessionEvent::Open {
+            conn,
+            at: self.now(),
+        });
+        Recording {
+            stream,
+            recorder: self.clone(),
+            conn,
+        }
+    }
+}
+
+/// A connection whose traffic goes to a [`Recorder`].
+#[derive(Debug)]
+pub struct Recording<S> {
+    stream: S,
+    recorder: Recorder,
+    conn: u64,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recording<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.stream).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let read = &buf.filled()[filled..];
+            if !read.is_empty() {
+                self.recorder.append(SessionEvent::In {
+                    conn: self.conn,
+                    at: self.recorder.now(),
+                    bytes: to_hex(read),
+                });
+            }
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Recording<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.recorder.append(SessionEvent::Out {
+                conn: self.conn,
+                at: self.recorder.now(),
+                bytes: to_hex(&buf[..written]),
+            });
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+impl<S> Drop for Recording<S> {
+    fn drop(&mut self) {
+        self.recorder.append(SessionEvent::Close {
+            conn: self.conn,
+            at: self.recorder.now(),
+        });
+    }
+}
+
+/// Everything recorded for one connection.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Session {
+    pub conn: u64,
+    pub opened: u64,
+    // When each chunk of client bytes arrived
+    pub inbound: Vec<(u64, Vec<u8>)>,
+    // Every byte the server sent back
+    pub outbound: Vec<u8>,
+    pub closed: Option<u64>,
+}
+
+fn invalid(line: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid recording at line {line}"),
+    )
+}
+
+/// The latest session of connection `conn`, which an event at `line` is
+/// about.
+fn session(sessions: &mut [Session], conn: u64, line: usize) -> io::Result<&mut Session> {
+    sessions
+        .iter_mut()
+        .rev()
+        .find(|session| session.conn == conn)
+        .ok_or_else(|| invalid(line))
+}
+
+/// Reads a recording back into one session per connection, in the order
+/// they were opened.
+pub fn read_sessions(path: impl AsRef<Path>) -> io::Result<Vec<Session>> {
+    let mut sessions: Vec<Session> = vec![];
+    for event in jsonl::read(path)? {
+        let (line, event): (usize, SessionEvent) = event?;
+        match event {
+            SessionEvent::Open { conn, at } => sessions.push(Session {
+                conn,
+                opened: at,
+                ..Session::default()
+            }),
+            SessionEvent::In { conn, at, bytes } => {
+                let bytes = from_hex(&bytes).ok_or_else(|| invalid(line))?;
+                session(&mut sessions, conn, line)?
+                    .inbound
+                    .push((at, bytes));
+            }
+            SessionEvent::Out { conn, bytes, .. } => {
+                let bytes = from_hex(&bytes).ok_or_else(|| invalid(line))?;
+                session(&mut sessions, conn, line)?.outbound.extend(bytes);
+            }
+            SessionEvent::Close { conn, at } => {
+                session(&mut sessions, conn, line)?.closed = Some(at);
+            }
+        }
+    }
+    Ok(sessions)
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::testing::temp_path;
+
+    #[test]
+    fn hex_roundtrip() {
+        assert_eq!(to_hex(&[0x20, 0xff, 0x00]), "20ff00");
+        assert_eq!(from_hex("20ff00"), Some(vec![0x20, 0xff, 0x00]));
+        assert_eq!(from_hex("2"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+
+    #[tokio::test]
+    async fn record_both_directions() {
+        let path = temp_path("recording");
+        let recorder = Recorder::create(&path).unwrap();
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = recorder.record(server);
+        client.write_all(&[0x40, 0, 0, 0, 10]).await.unwrap();
+        let mut request = [0; 5];
+        server.read_exact(&mut request).await.unwrap();
+        server.write_all(&[0x41]).await.unwrap();
+        let mut reply = [0; 1];
+        client.read_exact(&mut reply).await.unwrap();
+        // Everything queued is written once the last handle is gone.
+        drop(server);
+        drop(recorder);
+
+        let sessions = read_sessions(&path).unwrap();
+        assert_eq!(sessions.len(), 1);
+        let inbound: Vec<u8> = sessions[0]
+            .inbound
+            .iter()
+            .flat_map(|(_, bytes)| bytes.clone())
+            .collect();
+        assert_eq!(inbound, vec![0x40, 0, 0, 0, 10]);
+        assert_eq!(sessions[0].outbound, vec![0x41]);
+        assert!(sessions[0].closed.is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
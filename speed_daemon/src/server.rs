@@ -0,0 +1,1058 @@
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::select;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::time::{
+    self, interval, interval_at, sleep_until, Instant, Interval, MissedTickBehavior,
+};
+use tokio_util::codec::Framed;
+
+use crate::admin;
+use crate::audit::AuditLog;
+use crate::backend::{Backend, InMemory};
+use crate::codec::{ClientToServerMessage, MessageCodec, MessageCodecError, ServerToClientMessage};
+use crate::config::{Config, Enforcement};
+use crate::gossip::{Gossip, Update};
+use crate::plates::{OnInvalidPlate, PlateError, QuarantinedPlate};
+use crate::positions::{fastest_segment, speed, Position};
+use crate::record::Recorder;
+use crate::state::{day_span, CameraId, DispatcherId, State, Ticket, TicketState};
+use crate::store::{Observation, Record, Snapshot, Store};
+
+type ClientFramed<S> = Framed<S, MessageCodec>;
+
+// How often readings past the retention window and idle plates are dropped,
+// along with the tickets of plates left without readings.
+const SWEEP_EVERY: Duration = Duration::from_secs(60);
+
+/// Sends a single `Error` message with `reason`, closes the connection and
+/// returns the reason as an error so the caller stops reading.
+async fn protocol_error<S>(framed: &mut ClientFramed<S>, reason: String) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    framed
+        .send(ServerToClientMessage::Error(reason.clone()))
+        .await?;
+    framed.close().await?;
+    Err(anyhow::Error::msg(reason))
+}
+
+/// Waits for the next heartbeat, or forever if the client never asked for one.
+async fn tick(heartbeat: &mut Option<Interval>) {
+    match heartbeat {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Camera {
+    id: CameraId,
+    road: u16,
+    mile: u16,
+    limit: u16,
+}
+
+/// Applies the plate rules to a plate from `camera`. Invalid plates are
+/// counted and either quarantined, giving `None`, or returned as an error if
+/// the camera must be rejected.
+fn normalize_plate(
+    state: &State,
+    camera: &Camera,
+    plate: String,
+    timestamp: u32,
+) -> Result<Option<String>, PlateError> {
+    let rules = &state.config.plates;
+    let e = match rules.normalize(&plate) {
+        Ok(plate) => return Ok(Some(plate)),
+        Err(e) => e,
+    };
+    println!("invalid PLATE plate {plate:?}, timestamp: {timestamp}: {e}");
+    match rules.on_invalid {
+        OnInvalidPlate::Reject => {
+            state.invalid_plates.reject();
+            Err(e)
+        }
+        OnInvalidPlate::Quarantine => {
+            state.invalid_plates.quarantine(QuarantinedPlate {
+                plate,
+                road: camera.road,
+                mile: camera.mile,
+                timestamp,
+                reason: e.to_string(),
+            });
+            Ok(None)
+        }
+    }
+}
+
+async fn record_plate(state: &Arc<State>, camera: &Camera, plate: String, timestamp: u32) {
+    println!("PLATE plate {plate}, timestamp: {timestamp}");
+    let observation = Observation {
+        plate,
+        road: camera.road,
+        mile: camera.mile,
+        timestamp,
+    };
+    if observe(state, &observation).await {
+        state.backend.observed(&observation, camera.limit);
+    } else {
+        println!(
+            "duplicate PLATE plate {}, timestamp: {timestamp}",
+            observation.plate
+        );
+    }
+}
+
+/// Records a reading from a camera of this node or another one, and checks
+/// the pairs it forms if this node owns the plate. Returns whether the
+/// reading is new.
+async fn observe(state: &Arc<State>, observation: &Observation) -> bool {
+    let Observation {
+        plate,
+        road,
+        mile,
+        timestamp,
+    } = observation.clone();
+    let new = Position { timestamp, mile };
+    let Some(pairs) = state.positions.insert(&plate, road, new) else {
+        return false;
+    };
+    if let Some(store) = &state.store {
+        store.append(Record::Observation(observation.clone()));
+    }
+    if !state.backend.owns(&plate) {
+        return true;
+    }
+
+    let pairs = pairs.into_iter().map(|(prev, next)| (road, prev, next));
+    let window = state.config.reorder_window;
+    if window.is_zero() {
+        check_pairs(state, &plate, pairs.collect()).await;
+        return true;
+    }
+
+    // Readings of a plate that are still on their way may land between these,
+    // so each pair waits out the window before it is checked.
+    let due = Instant::now() + window;
+    {
+        let mut settling = state.settling.lock().unwrap();
+        let settling = settling.entry(plate.clone()).or_default();
+        settling
+            .pairs
+            .extend(pairs.map(|(road, prev, next)| (due, road, prev, next)));
+    }
+    let state = state.clone();
+    tokio::spawn(async move {
+        sleep_until(due).await;
+        // Check the plate's pairs as if its readings had arrived in timestamp
+        // order, until the next one isn't due yet. Its own task goes on from
+        // there, or the task already checking this plate does.
+        let mut checking = false;
+        loop {
+            let pairs = {
+                let mut settling = state.settling.lock().unwrap();
+                let Some(entry) = settling.get_mut(&plate) else {
+                    return;
+                };
+                if entry.checking && !checking {
+                    return;
+                }
+                let pairs = entry.take_ready(Instant::now());
+                entry.checking = !pairs.is_empty();
+                if pairs.is_empty() {
+                    if entry.pairs.is_empty() {
+                        settling.remove(&plate);
+                    }
+                    return;
+                }
+                pairs
+            };
+            checking = true;
+            check_pairs(&state, &plate, pairs).await;
+        }
+    });
+    true
+}
+
+/// Tickets every pair of readings that is still adjacent and over the limit,
+/// or in average mode the fastest chain of readings around such a pair.
+async fn check_pairs(state: &State, plate: &str, pairs: Vec<(u16, Position, Position)>) {
+    for (road, prev, next) in pairs {
+        // Readings from other nodes come with their road's limit, but the
+        // limit of a road only known from the store is lost on restart.
+        let Some(limit) = state.roads.limit(road) else {
+            println!("unknown limit of road {road}, not checking PLATE plate {plate}");
+            continue;
+        };
+        if !state.positions.adjacent(plate, road, prev, next) {
+            continue;
+        }
+        let segment = match state.config.enforcement {
+            Enforcement::Pairs => speed(prev, next).map(|speed| (prev, next, speed)),
+            Enforcement::Average => {
+                fastest_segment(&state.positions.chain(plate, road, prev, next), prev, next)
+            }
+        };
+        let Some((prev, next, speed)) = segment else {
+            continue;
+        };
+        if speed >= limit as u32 * 100 + state.config.tolerance {
+            let (first_day, last_day) = day_span(prev.timestamp, next.timestamp);
+            let mut ticket_state = state.ticket_state.lock().unwrap();
+            if ticket_state.claim_days(plate, first_day, last_day) {
+                println!(
+                    "TICKET plate {plate}, road {road}, speed {}",
+                    speed as f64 / 100.0
+                );
+                let ticket = Ticket {
+                    plate: plate.to_owned(),
+                    road,
+                    mile1: prev.mile,
+                    timestamp1: prev.timestamp,
+                    mile2: next.mile,
+                    timestamp2: next.timestamp,
+                    speed: speed.min(u16::MAX as u32) as u16,
+                };
+                state.backend.ticketed_days(plate, first_day, last_day);
+                if let Some(audit) = &state.audit {
+                    audit.issued(&ticket);
+                }
+                // The dispatchers for the road may all be on other nodes.
+                let handed_over = !ticket_state.has_dispatcher(road)
+                    && state.backend.hand_over(ticket.clone()).is_ok();
+                if let Some(store) = &state.store {
+                    if !handed_over {
+                        store.append(Record::Ticket(ticket.clone()));
+                    }
+                    store.append(Record::TicketedDays {
+                        plate: plate.to_owned(),
+                        first_day,
+                        last_day,
+                    });
+                }
+                if handed_over {
+                    ticket_state.issued_elsewhere(ticket);
+                } else {
+                    ticket_state.issue(ticket);
+                }
+            }
+        }
+    }
+}
+
+/// Tells the other nodes which roads this node has dispatchers for now.
+fn announce_dispatchers(state: &State, ticket_state: &TicketState) {
+    let roads: Vec<u16> = ticket_state.dispatchers().into_keys().collect();
+    state.backend.dispatchers_changed(&roads);
+}
+
+/// Hands the tickets waiting on any of `roads` without a dispatcher here to
+/// another node that has one.
+fn hand_over_pending(state: &State, ticket_state: &mut TicketState, roads: &[u16]) {
+    for ticket in ticket_state.take_undeliverable(roads) {
+        if let Err(ticket) = state.backend.hand_over(ticket) {
+            ticket_state.dispatch(ticket);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Identity {
+    Camera(Camera),
+    Dispatcher { id: DispatcherId, roads: Vec<u16> },
+}
+
+/// Serves a single camera or dispatcher connected through `stream`.
+pub async fn handle<S>(stream: S, state: Arc<State>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut identified = None;
+    let (sender, mut receiver) = mpsc::channel(state.config.dispatcher_queue);
+    let result = handle_messages(stream, &state, &mut identified, sender, &mut receiver).await;
+
+    match identified {
+        Some(Identity::Camera(camera)) => state.roads.remove_camera(camera.road, camera.id),
+        Some(Identity::Dispatcher { id, roads }) => {
+            // Re-queue whatever this dispatcher never got to write. It may
+            // already be removed if it was too slow to take a ticket.
+            let mut ticket_state = state.ticket_state.lock().unwrap();
+            ticket_state.remove_dispatcher(id);
+            receiver.close();
+            while let Ok(ticket) = receiver.try_recv() {
+                ticket_state.dispatch(ticket);
+            }
+            announce_dispatchers(&state, &ticket_state);
+            hand_over_pending(&state, &mut ticket_state, &roads);
+        }
+        None => {}
+    }
+
+    result
+}
+
+async fn handle_messages<S>(
+    stream: S,
+    state: &Arc<State>,
+    identified: &mut Option<Identity>,
+    sender: Sender<Ticket>,
+    receiver: &mut Receiver<Ticket>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Owned by this task, so the timer stops as soon as the connection does.
+    let mut heartbeat_requested = false;
+    let mut heartbeat: Option<Interval> = None;
+
+    let mut framed = Framed::new(stream, MessageCodec::new());
+    loop {
+        select! {
+            message = framed.next() => {
+                let message = match message {
+                    None => return Ok(()),
+                    Some(Ok(message)) => message,
+                    Some(Err(MessageCodecError::Io(e))) => return Err(e.into()),
+                    Some(Err(e)) => return protocol_error(&mut framed, e.to_string()).await,
+                };
+                match message {
+                    ClientToServerMessage::Plate { plate, timestamp } => match identified {
+                        Some(Identity::Camera(camera)) => {
+                            match normalize_plate(state, camera, plate, timestamp) {
+                                Ok(Some(plate)) => record_plate(state, camera, plate, timestamp).await,
+                                Ok(None) => {}
+                                Err(e) => {
+                                    return protocol_error(&mut framed, format!("invalid plate: {e}")).await;
+                                }
+                            }
+                        }
+                        Some(Identity::Dispatcher { .. }) => {
+                            return protocol_error(&mut framed, "plate from Dispatcher".to_string())
+                                .await;
+                        }
+                        None => {
+                            return protocol_error(
+                                &mut framed,
+                                "plate from unidentified client".to_string(),
+                            )
+                            .await;
+                        }
+                    },
+                    ClientToServerMessage::WantHeartbeat { interval } => {
+                        if heartbeat_requested {
+                            return protocol_error(&mut framed, "double WANT_HEARTBEAT".to_string())
+                                .await;
+                        }
+                        heartbeat_requested = true;
+                        println!("WANT_HEARTBEAT {interval}");
+                        if interval > 0 {
+                            // The interval is in deciseconds and the first heartbeat is only due
+                            // once a full interval has passed.
+                            let period = Duration::from_millis(interval as u64 * 100);
+                            let mut interval = interval_at(Instant::now() + period, period);
+                            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                            heartbeat = Some(interval);
+                        }
+                    }
+                    ClientToServerMessage::IAmCamera { road, mile, limit } => {
+                        if identified.is_some() {
+                            return protocol_error(&mut framed, "double I_AM_CAMERA".to_string())
+                                .await;
+                        }
+                        println!("I_AM_CAMERA road {road}, mile {mile}, limit {limit}");
+                        let id = match state.roads.add_camera(road, mile, limit) {
+                            Ok(id) => id,
+                            Err(known) => {
+                                return protocol_error(
+                                    &mut framed,
+                                    format!("limit {limit} conflicts with limit {known} of road {road}"),
+                                )
+                                .await;
+                            }
+                        };
+                        *identified = Some(Identity::Camera(Camera {
+                            id,
+                            road,
+                            mile,
+                            limit,
+                        }));
+                    }
+                    ClientToServerMessage::IAmDispatcher { roads } => {
+                        if identified.is_some() {
+                            return protocol_error(&mut framed, "double I_AM_DISPATCHER".to_string())
+                                .await;
+                        }
+                        println!("I_AM_DISPATCHER {roads:?}");
+                        let mut ticket_state = state.ticket_state.lock().unwrap();
+                        let id = ticket_state.add_dispatcher(&roads, sender.clone());
+                        announce_dispatchers(state, &ticket_state);
+                        drop(ticket_state);
+                        *identified = Some(Identity::Dispatcher { id, roads });
+                    }
+                }
+            }
+            _ = tick(&mut heartbeat) => {
+                framed.send(ServerToClientMessage::Heartbeat).await?;
+            }
+            Some(ticket) = receiver.recv() => {
+                println!("will send ticket: {ticket:?}");
+                deliver(state, &mut framed, identified, receiver, ticket).await?;
+            }
+        }
+    }
+}
+
+/// Writes `ticket` to the dispatcher on `framed`. A dispatcher that fails to
+/// take it within the dispatcher timeout is dropped and the ticket goes to
+/// another dispatcher for the road.
+async fn deliver<S>(
+    state: &State,
+    framed: &mut ClientFramed<S>,
+    identified: &Option<Identity>,
+    receiver: &Receiver<Ticket>,
+    ticket: Ticket,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let timeout = state.config.dispatcher_timeout;
+    let result = match time::timeout(timeout, framed.send(ticket.clone().into())).await {
+        Ok(result) => result.map_err(anyhow::Error::from),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "dispatcher took longer than {timeout:?} to accept a ticket"
+        ))),
+    };
+    if let Err(e) = result {
+        let mut ticket_state = state.ticket_state.lock().unwrap();
+        if let Some(Identity::Dispatcher { id, .. }) = identified {
+            ticket_state.remove_dispatcher(*id);
+        }
+        ticket_state.dispatch(ticket);
+        return Err(e);
+    }
+
+    if let (Some(audit), Some(Identity::Dispatcher { id, .. })) = (&state.audit, identified) {
+        audit.delivered(&ticket, *id);
+    }
+    if let Some(store) = &state.store {
+        store.append(Record::Delivered(ticket));
+    }
+    // Take on tickets that waited while this dispatcher was full.
+    match identified {
+        Some(Identity::Dispatcher { id, .. }) if receiver.is_empty() => {
+            state.ticket_state.lock().unwrap().refill(*id);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Applies what other nodes tell this one.
+async fn apply_updates(state: Arc<State>, mut updates: Receiver<Update>) {
+    while let Some(update) = updates.recv().await {
+        match update {
+            Update::Observation { observation, limit } => {
+                state.roads.learn_limit(observation.road, limit);
+                observe(&state, &observation).await;
+            }
+            Update::TicketedDays {
+                plate,
+                first_day,
+                last_day,
+            } => {
+                if let Some(store) = &state.store {
+                    store.append(Record::TicketedDays {
+                        plate: plate.clone(),
+                        first_day,
+                        last_day,
+                    });
+                }
+                let mut ticket_state = state.ticket_state.lock().unwrap();
+                let days = ticket_state.days.entry(plate).or_default();
+                days.insert(first_day, last_day);
+            }
+            Update::Ticket(ticket) => {
+                if let Some(store) = &state.store {
+                    store.append(Record::Ticket(ticket.clone()));
+                }
+                state.ticket_state.lock().unwrap().dispatch(ticket);
+            }
+            Update::Dispatchers { roads, .. } => {
+                let mut ticket_state = state.ticket_state.lock().unwrap();
+                hand_over_pending(&state, &mut ticket_state, &roads);
+            }
+        }
+    }
+}
+
+/// Accepts cameras and dispatchers on `listener` until it fails.
+pub async fn serve(listener: TcpListener, config: Config) -> Result<()> {
+    let (store, snapshot) = match &config.store_dir {
+        Some(dir) => {
+            let (store, snapshot) = Store::open(dir, config.retention)?;
+            (Some(store), snapshot)
+        }
+        None => (None, Snapshot::default()),
+    };
+    let audit = match &config.audit_file {
+        Some(path) => Some(AuditLog::open(path)?),
+        None => None,
+    };
+    let recorder = match &config.record_file {
+        Some(path) => Some(Recorder::create(path)?),
+        None => None,
+    };
+    let (backend, updates): (Box<dyn Backend>, _) = match &config.gossip_addr {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            let (gossip, updates) = Gossip::start(listener, addr.clone(), &config.peers);
+            (Box::new(gossip), Some(updates))
+        }
+        None => (Box::new(InMemory), None),
+    };
+    let admin_addr = config.admin_addr.clone();
+    let state = Arc::new(State::restore(snapshot, store, audit, backend, config));
+
+    if let Some(updates) = updates {
+        tokio::spawn(apply_updates(state.clone(), updates));
+    }
+
+    let retention = state.config.retention;
+    if retention.window.is_some() || retention.idle_days.is_some() {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let mut sweep = interval(SWEEP_EVERY);
+            loop {
+                sweep.tick().await;
+                state.positions.sweep();
+                if let Some(cutoff) = retention.forget_tickets_before(state.positions.latest()) {
+                    let seen = state.positions.plates();
+                    state
+                        .ticket_state
+                        .lock()
+                        .unwrap()
+                        .forget_before(cutoff, &seen);
+                }
+            }
+        });
+    }
+
+    if let Some(addr) = admin_addr {
+        let admin = TcpListener::bind(addr).await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve_admin(admin, state).await {
+                println!("admin interface stopped; error = {:?}", e);
+            }
+        });
+    }
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let state = state.clone();
+        let recorder = recorder.clone();
+        tokio::spawn(async move {
+            let result = match recorder {
+                Some(recorder) => handle(recorder.record(stream), state).await,
+                None => handle(stream, state).await,
+            };
+            if let Err(e) = result {
+                println!("an error occured; error = {:?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time::{sleep, timeout, Instant};
+    use tokio_util::codec::Framed;
+
+    use crate::backend::InMemory;
+    use crate::codec::{ClientCodec, ClientToServerMessage, ServerToClientMessage};
+    use crate::config::Config;
+    use crate::plates::OnInvalidPlate;
+    use crate::state::{State, Ticket};
+    use crate::store::Snapshot;
+
+    async fn start_server() -> SocketAddr {
+        start_server_with(Config::default()).await
+    }
+
+    async fn start_server_with(config: Config) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(super::serve(listener, config));
+        addr
+    }
+
+    async fn client(
+        addr: SocketAddr,
+        hello: ClientToServerMessage,
+    ) -> Framed<TcpStream, ClientCodec> {
+        let mut framed = Framed::new(TcpStream::connect(addr).await.unwrap(), ClientCodec::new());
+        framed.send(hello).await.unwrap();
+        framed
+    }
+
+    /// An address nothing listens on, for a node to gossip on.
+    async fn free_addr() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    async fn read_to_end(stream: &mut TcpStream) -> Vec<u8> {
+        let mut buf = vec![];
+        timeout(Duration::from_secs(1), stream.read_to_end(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn heartbeat_after_interval() {
+        let addr = start_server().await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let start = Instant::now();
+        client.write_all(&[0x40, 0, 0, 0, 2]).await.unwrap();
+        for n in 1..=2 {
+            assert_eq!(client.read_u8().await.unwrap(), 0x41);
+            assert!(start.elapsed() >= Duration::from_millis(200 * n));
+        }
+    }
+
+    /// Sends `bytes` on a fresh connection and returns everything the server
+    /// writes back before it closes the connection.
+    async fn reply_to(addr: SocketAddr, bytes: &[u8]) -> Vec<u8> {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(bytes).await.unwrap();
+        read_to_end(&mut client).await
+    }
+
+    fn error_frame(reason: &str) -> Vec<u8> {
+        let mut frame = vec![0x10, reason.len() as u8];
+        frame.extend_from_slice(reason.as_bytes());
+        frame
+    }
+
+    #[tokio::test]
+    async fn double_want_heartbeat() {
+        let addr = start_server().await;
+        assert_eq!(
+            reply_to(addr, &[0x40, 0, 0, 0, 0, 0x40, 0, 0, 0, 0]).await,
+            error_frame("double WANT_HEARTBEAT")
+        );
+    }
+
+    #[tokio::test]
+    async fn one_error_per_protocol_violation() {
+        let addr = start_server().await;
+        let camera = [0x80, 0, 66, 0, 8, 0, 60];
+        let dispatcher = [0x81, 1, 0, 66];
+        let plate = [0x20, 4, b'U', b'N', b'1', b'X', 0, 0, 0, 0];
+        let cases: [(Vec<u8>, &str); 6] = [
+            (vec![0x21], "unexpected message with id: 0x21"),
+            ([camera, camera].concat(), "double I_AM_CAMERA"),
+            (
+                [&camera[..], &dispatcher[..]].concat(),
+                "double I_AM_DISPATCHER",
+            ),
+            ([dispatcher, dispatcher].concat(), "double I_AM_DISPATCHER"),
+            (
+                [&dispatcher[..], &plate[..]].concat(),
+                "plate from Dispatcher",
+            ),
+            (plate.to_vec(), "plate from unidentified client"),
+        ];
+        for (bytes, reason) in cases {
+            assert_eq!(
+                reply_to(addr, &bytes).await,
+                error_frame(reason),
+                "{reason}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn late_reading_lands_between_pair() {
+        let addr = start_server_with(Config {
+            reorder_window: Duration::from_millis(200),
+            ..Config::default()
+        })
+        .await;
+        let mut dispatcher = client(
+            addr,
+            ClientToServerMessage::IAmDispatcher { roads: vec![66] },
+        )
+        .await;
+        let mut cameras = vec![];
+        for mile in [0, 100, 101] {
+            let camera = ClientToServerMessage::IAmCamera {
+                road: 66,
+                mile,
+                limit: 60,
+            };
+            cameras.push(client(addr, camera).await);
+        }
+
+        // 0 -> 101 alone would be 98 mph, but the car did 120 mph up to mile
+        // 100 and crawled the last mile.
+        for (camera, timestamp) in [(0, 0), (2, 3700), (1, 3000)] {
+            let plate = ClientToServerMessage::Plate {
+                plate: "UN1X".to_string(),
+                timestamp,
+            };
+            cameras[camera].send(plate).await.unwrap();
+            sleep(Duration::from_millis(20)).await;
+        }
+
+        let ticket = timeout(Duration::from_secs(1), dispatcher.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            ticket,
+            ServerToClientMessage::Ticket {
+                plate: "UN1X".to_string(),
+                road: 66,
+                mile1: 0,
+                timestamp1: 0,
+                mile2: 100,
+                timestamp2: 3000,
+                speed: 12000,
+            }
+        );
+        assert!(timeout(Duration::from_millis(300), dispatcher.next())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn readings_in_reverse_order() {
+        let addr = start_server_with(Config {
+            reorder_window: Duration::from_millis(200),
+            ..Config::default()
+        })
+        .await;
+        let mut dispatcher = client(
+            addr,
+            ClientToServerMessage::IAmDispatcher { roads: vec![66] },
+        )
+        .await;
+        let mut cameras = vec![];
+        for mile in [0, 100, 200] {
+            let camera = ClientToServerMessage::IAmCamera {
+                road: 66,
+                mile,
+                limit: 60,
+            };
+            cameras.push(client(addr, camera).await);
+        }
+
+        // 120 mph all the way, so the first pair of the day gets the ticket
+        // however the readings arrive.
+        let readings = [(0, 0), (1, 3000), (2, 6000)];
+        for (plate, order) in [("UN1X", [0, 1, 2]), ("RE05BKG", [2, 1, 0])] {
+            for i in order {
+                let (camera, timestamp) = readings[i];
+                let message = ClientToServerMessage::Plate {
+                    plate: plate.to_string(),
+                    timestamp,
+                };
+                cameras[camera].send(message).await.unwrap();
+                sleep(Duration::from_millis(20)).await;
+            }
+        }
+
+        let mut tickets = vec![];
+        for _ in 0..2 {
+            let ticket = timeout(Duration::from_secs(1), dispatcher.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            let ServerToClientMessage::Ticket {
+                plate,
+                mile1,
+                mile2,
+                ..
+            } = ticket
+            else {
+                panic!("not a ticket: {ticket:?}");
+            };
+            tickets.push((plate, mile1, mile2));
+        }
+        tickets.sort();
+        assert_eq!(
+            tickets,
+            [
+                ("RE05BKG".to_string(), 0, 100),
+                ("UN1X".to_string(), 0, 100)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn plate_seen_more_often_than_window() {
+        let addr = start_server_with(Config {
+            reorder_window: Duration::from_millis(200),
+            ..Config::default()
+        })
+        .await;
+        let mut dispatcher = client(
+            addr,
+            ClientToServerMessage::IAmDispatcher { roads: vec![66] },
+        )
+        .await;
+        let mut cameras = vec![];
+        for mile in [0, 100] {
+            let camera = ClientToServerMessage::IAmCamera {
+                road: 66,
+                mile,
+                limit: 60,
+            };
+            cameras.push(client(addr, camera).await);
+        }
+
+        // 120 mph, then parked in front of the second camera, which keeps
+        // seeing the car well within the window.
+        for (camera, timestamp) in [(0, 0), (1, 3000)] {
+            let plate = ClientToServerMessage::Plate {
+                plate: "UN1X".to_string(),
+                timestamp,
+            };
+            cameras[camera].send(plate).await.unwrap();
+        }
+        let mut parked = cameras.pop().unwrap();
+        tokio::spawn(async move {
+            for timestamp in 3001..3100 {
+                let plate = ClientToServerMessage::Plate {
+                    plate: "UN1X".to_string(),
+                    timestamp,
+                };
+                parked.send(plate).await.unwrap();
+                sleep(Duration::from_millis(20)).await;
+            }
+        });
+
+        let ticket = timeout(Duration::from_millis(600), dispatcher.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            ticket,
+            ServerToClientMessage::Ticket { speed: 12000, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn invalid_plates() {
+        let camera = [0x80, 0, 66, 0, 8, 0, 60];
+        let plate = [0x20, 2, b'U', 0xff, 0, 0, 0, 0];
+
+        // Quarantined, the camera stays connected.
+        let mut config = Config::default();
+        config.plates.on_invalid = OnInvalidPlate::Quarantine;
+        let addr = start_server_with(config).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&camera).await.unwrap();
+        client.write_all(&plate).await.unwrap();
+        client.write_all(&[0x40, 0, 0, 0, 1]).await.unwrap();
+        assert_eq!(client.read_u8().await.unwrap(), 0x41);
+
+        // Rejected by default.
+        let addr = start_server().await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&camera).await.unwrap();
+        client.write_all(&plate).await.unwrap();
+        let reply = read_to_end(&mut client).await;
+        assert_eq!(reply[0], 0x10);
+        assert_eq!(
+            &reply[2..],
+            "invalid plate: plate contains '\u{fffd}'".as_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_dispatcher_is_dropped() {
+        let config = Config {
+            dispatcher_timeout: Duration::from_millis(100),
+            ..Config::default()
+        };
+        let state = Arc::new(State::restore(
+            Snapshot::default(),
+            None,
+            None,
+            Box::new(InMemory),
+            config,
+        ));
+        // A dispatcher for road 66 that never reads what it is sent.
+        let (mut client, server) = tokio::io::duplex(64);
+        let slow = tokio::spawn(super::handle(server, state.clone()));
+        client.write_all(&[0x81, 1, 0, 66]).await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+
+        let start = Instant::now();
+        for timestamp2 in 1..=10 {
+            state.ticket_state.lock().unwrap().issue(Ticket {
+                plate: "UN1X".to_string(),
+                road: 66,
+                mile1: 0,
+                timestamp1: 0,
+                mile2: 100,
+                timestamp2,
+                speed: 12000,
+            });
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        let result = timeout(Duration::from_secs(1), slow)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(result.is_err());
+        let mut written = vec![];
+        client.read_to_end(&mut written).await.unwrap();
+        // Every ticket that wasn't written in full waits for another dispatcher.
+        let ticket_state = state.ticket_state.lock().unwrap();
+        assert!(ticket_state.dispatchers().is_empty());
+        assert_eq!(ticket_state.pending()[&66].len() + written.len() / 22, 10);
+    }
+
+    #[tokio::test]
+    async fn nodes_share_readings() {
+        let gossip = [free_addr().await, free_addr().await];
+        let mut nodes = vec![];
+        for (i, addr) in gossip.iter().enumerate() {
+            let config = Config {
+                reorder_window: Duration::from_millis(100),
+                gossip_addr: Some(addr.clone()),
+                peers: vec![gossip[1 - i].clone()],
+                ..Config::default()
+            };
+            nodes.push(start_server_with(config).await);
+        }
+        // Give the nodes time to dial each other.
+        sleep(Duration::from_millis(700)).await;
+
+        // The car passes a camera of each node, and the only dispatcher is on
+        // the second one.
+        let mut dispatcher = client(
+            nodes[1],
+            ClientToServerMessage::IAmDispatcher { roads: vec![66] },
+        )
+        .await;
+        let mut cameras = vec![];
+        for (node, mile) in [(nodes[0], 0), (nodes[1], 100)] {
+            let camera = ClientToServerMessage::IAmCamera {
+                road: 66,
+                mile,
+                limit: 60,
+            };
+            cameras.push(client(node, camera).await);
+        }
+        sleep(Duration::from_millis(50)).await;
+        for (camera, timestamp) in [(0, 0), (1, 3000)] {
+            let plate = ClientToServerMessage::Plate {
+                plate: "UN1X".to_string(),
+                timestamp,
+            };
+            cameras[camera].send(plate).await.unwrap();
+        }
+
+        let ticket = timeout(Duration::from_secs(2), dispatcher.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            ticket,
+            ServerToClientMessage::Ticket {
+                plate: "UN1X".to_string(),
+                road: 66,
+                mile1: 0,
+                timestamp1: 0,
+                mile2: 100,
+                timestamp2: 3000,
+                speed: 12000,
+            }
+        );
+        assert!(timeout(Duration::from_millis(300), dispatcher.next())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn owner_without_cameras_on_road() {
+        let gossip = [free_addr().await, free_addr().await];
+        let mut nodes = vec![];
+        for (i, addr) in gossip.iter().enumerate() {
+            let config = Config {
+                gossip_addr: Some(addr.clone()),
+                peers: vec![gossip[1 - i].clone()],
+                ..Config::default()
+            };
+            nodes.push(start_server_with(config).await);
+        }
+        sleep(Duration::from_millis(700)).await;
+
+        // Cameras and dispatcher are all on the first node, but the plate
+        // belongs to the second, which only learns the limit from gossip.
+        let mut sorted = gossip.to_vec();
+        sorted.sort();
+        let plate = (0..)
+            .map(|n| format!("CAR{n}"))
+            .find(|plate| crate::gossip::owner(&sorted, plate) == gossip[1])
+            .unwrap();
+        let mut dispatcher = client(
+            nodes[0],
+            ClientToServerMessage::IAmDispatcher { roads: vec![66] },
+        )
+        .await;
+        let mut cameras = vec![];
+        for mile in [0, 100] {
+            let camera = ClientToServerMessage::IAmCamera {
+                road: 66,
+                mile,
+                limit: 60,
+            };
+            cameras.push(client(nodes[0], camera).await);
+        }
+        sleep(Duration::from_millis(50)).await;
+        for (camera, timestamp) in [(0, 0), (1, 3000)] {
+            let message = ClientToServerMessage::Plate {
+                plate: plate.clone(),
+                timestamp,
+            };
+            cameras[camera].send(message).await.unwrap();
+        }
+
+        let ticket = timeout(Duration::from_secs(2), dispatcher.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            ticket,
+            ServerToClientMessage::Ticket { speed: 12000, .. }
+        ));
+    }
+}
@@ -1,17 +1,632 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 
-use tokio::sync::oneshot::Sender;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender;
+use tokio::time::Instant;
 
+use crate::audit::AuditLog;
+use crate::backend::Backend;
 use crate::codec::ServerToClientMessage;
+use crate::config::Config;
+use crate::plates::InvalidPlates;
+use crate::positions::{Position, Positions};
+use crate::store::{Snapshot, Store};
 
-struct Position {
-    timestamp: u32,
-    mile: u16,
+pub type DispatcherId = u64;
+
+pub const DAY: u32 = 24 * 60 * 60;
+
+/// Returns the first and last day covered by the time between two readings.
+pub fn day_span(timestamp1: u32, timestamp2: u32) -> (u32, u32) {
+    (
+        timestamp1.min(timestamp2) / DAY,
+        timestamp1.max(timestamp2) / DAY,
+    )
+}
+
+/// Days a plate has been ticketed on, kept as disjoint, non-adjacent
+/// inclusive ranges.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(from = "Vec<(u32, u32)>", into = "Vec<(u32, u32)>")]
+pub struct TicketedDays {
+    // First day -> last day
+    ranges: BTreeMap<u32, u32>,
+}
+
+impl TicketedDays {
+    /// Whether any day in `first..=last` has been ticketed already.
+    pub fn overlaps(&self, first: u32, last: u32) -> bool {
+        // Ranges are disjoint, so the one starting closest before `last` also
+        // ends the latest among those that could overlap.
+        match self.ranges.range(..=last).next_back() {
+            Some((_, &end)) => end >= first,
+            None => false,
+        }
+    }
+
+    pub fn insert(&mut self, first: u32, last: u32) {
+        let mut first = first;
+        let mut last = last;
+        let touching: Vec<_> = self
+            .ranges
+            .range(..=last.saturating_add(1))
+            .rev()
+            .take_while(|(_, &end)| end.saturating_add(1) >= first)
+            .map(|(&start, &end)| (start, end))
+            .collect();
+        for (start, end) in touching {
+            self.ranges.remove(&start);
+            first = first.min(start);
+            last = last.max(end);
+        }
+        self.ranges.insert(first, last);
+    }
+
+    pub fn ranges(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.ranges.iter().map(|(&first, &last)| (first, last))
+    }
+
+    /// Drops the ranges that end before `day`.
+    pub fn forget_before(&mut self, day: u32) {
+        self.ranges.retain(|_, last| *last >= day);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+/// Drops the ticketed days before `cutoff` of every plate that `seen` no
+/// longer has readings for, and such plates once they have none left.
+pub fn forget_days(
+    days: &mut HashMap<String, TicketedDays>,
+    cutoff: u32,
+    seen: impl Fn(&str) -> bool,
+) {
+    days.retain(|plate, days| {
+        if !seen(plate) {
+            days.forget_before(cutoff / DAY);
+        }
+        !days.is_empty()
+    });
+}
+
+impl From<Vec<(u32, u32)>> for TicketedDays {
+    fn from(ranges: Vec<(u32, u32)>) -> Self {
+        let mut days = TicketedDays::default();
+        for (first, last) in ranges {
+            days.insert(first, last);
+        }
+        days
+    }
 }
 
+impl From<TicketedDays> for Vec<(u32, u32)> {
+    fn from(days: TicketedDays) -> Self {
+        days.ranges().collect()
+    }
+}
+
+// Ticket to be sent out when dispatcher for given road is ready
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Ticket {
+    pub plate: String,
+    pub road: u16,
+    pub mile1: u16,
+    pub timestamp1: u32,
+    pub mile2: u16,
+    pub timestamp2: u32,
+    pub speed: u16,
+}
+
+/// Gives back any other message.
+impl TryFrom<ServerToClientMessage> for Ticket {
+    type Error = ServerToClientMessage;
+
+    fn try_from(message: ServerToClientMessage) -> Result<Self, Self::Error> {
+        match message {
+            ServerToClientMessage::Ticket {
+                plate,
+                road,
+                mile1,
+                timestamp1,
+                mile2,
+                timestamp2,
+                speed,
+            } => Ok(Ticket {
+                plate,
+                road,
+                mile1,
+                timestamp1,
+                mile2,
+                timestamp2,
+                speed,
+            }),
+            other => Err(other),
+        }
+    }
+}
+
+impl From<Ticket> for ServerToClientMessage {
+    fn from(ticket: Ticket) -> Self {
+        ServerToClientMessage::Ticket {
+            plate: ticket.plate,
+            road: ticket.road,
+            mile1: ticket.mile1,
+            timestamp1: ticket.timestamp1,
+            mile2: ticket.mile2,
+            timestamp2: ticket.timestamp2,
+            speed: ticket.speed,
+        }
+    }
+}
 
+#[derive(Debug, Default)]
+pub struct TicketState {
+    next_dispatcher: DispatcherId,
+
+    // Road -> every connected dispatcher for that road, oldest first
+    dispatchers: HashMap<u16, Vec<(DispatcherId, Sender<Ticket>)>>,
+
+    // Road -> tickets waiting for a dispatcher
+    tickets: HashMap<u16, Vec<Ticket>>,
+    // Tickets a road keeps before dropping the oldest, unlimited if unset
+    max_pending: Option<usize>,
+    // Tickets dropped because their road kept too many
+    pub dropped: u64,
+
+    // Plate -> tickets issued since startup
+    issued: HashMap<String, Vec<Ticket>>,
+
+    // Plate -> Days with tickets
+    pub days: HashMap<String, TicketedDays>,
+}
+
+impl TicketState {
+    /// Marks `first..=last` as ticketed for `plate`, unless one of those days
+    /// already has a ticket.
+    pub fn claim_days(&mut self, plate: &str, first: u32, last: u32) -> bool {
+        let days = self.days.entry(plate.to_owned()).or_default();
+        if days.overlaps(first, last) {
+            return false;
+        }
+        days.insert(first, last);
+        true
+    }
+
+    /// Registers a dispatcher for `roads` and hands it every ticket that was
+    /// waiting for one of them.
+    pub fn add_dispatcher(&mut self, roads: &[u16], sender: Sender<Ticket>) -> DispatcherId {
+        let id = self.next_dispatcher;
+        self.next_dispatcher += 1;
+        for road in roads {
+            let dispatchers = self.dispatchers.entry(*road).or_default();
+            if dispatchers.iter().all(|(other, _)| *other != id) {
+                dispatchers.push((id, sender.clone()));
+            }
+            for ticket in self.tickets.remove(road).unwrap_or_default() {
+                self.dispatch(ticket);
+            }
+        }
+        id
+    }
+
+    /// Forgets a dispatcher. Tickets it did not write must be passed back to
+    /// [`TicketState::dispatch`] by the caller.
+    pub fn remove_dispatcher(&mut self, id: DispatcherId) {
+        self.dispatchers.retain(|_, dispatchers| {
+            dispatchers.retain(|(other, _)| *other != id);
+            !dispatchers.is_empty()
+        });
+    }
+
+    /// Records a newly issued ticket and dispatches it.
+    pub fn issue(&mut self, ticket: Ticket) {
+        self.issued_elsewhere(ticket.clone());
+        self.dispatch(ticket);
+    }
+
+    /// Records a newly issued ticket that another node delivers.
+    pub fn issued_elsewhere(&mut self, ticket: Ticket) {
+        self.issued
+            .entry(ticket.plate.clone())
+            .or_default()
+            .push(ticket);
+    }
+
+    pub fn has_dispatcher(&self, road: u16) -> bool {
+        self.dispatchers.contains_key(&road)
+    }
+
+    /// Takes the tickets waiting on any of `roads` that has no dispatcher
+    /// here, so another node's dispatchers can have them.
+    pub fn take_undeliverable(&mut self, roads: &[u16]) -> Vec<Ticket> {
+        let mut tickets = vec![];
+        for road in roads {
+            if !self.has_dispatcher(*road) {
+                tickets.extend(self.tickets.remove(road).unwrap_or_default());
+            }
+        }
+        tickets
+    }
+
+    /// Sends `ticket` to a live dispatcher for its road that has room for
+    /// it, or keeps it until one does.
+    pub fn dispatch(&mut self, mut ticket: Ticket) {
+        if let Some(dispatchers) = self.dispatchers.get_mut(&ticket.road) {
+            let mut i = 0;
+            while i < dispatchers.len() {
+                match dispatchers[i].1.try_send(ticket) {
+                    Ok(()) => return,
+                    Err(TrySendError::Full(t)) => {
+                        ticket = t;
+                        i += 1;
+                    }
+                    Err(TrySendError::Closed(t)) => {
+                        // The dispatcher went away without being removed yet.
+                        ticket = t;
+                        dispatchers.remove(i);
+                    }
+                }
+            }
+        }
+
+        let pending = self.tickets.entry(ticket.road).or_default();
+        if self.max_pending.is_some_and(|max| pending.len() >= max) {
+            let dropped = pending.remove(0);
+            println!("too many pending tickets, dropping {dropped:?}");
+            self.dropped += 1;
+        }
+        pending.push(ticket);
+    }
+
+    /// Dispatches tickets waiting on the roads of dispatcher `id`, once it
+    /// has room again.
+    pub fn refill(&mut self, id: DispatcherId) {
+        let roads: Vec<u16> = self
+            .dispatchers
+            .iter()
+            .filter(|(_, dispatchers)| dispatchers.iter().any(|(other, _)| *other == id))
+            .map(|(road, _)| *road)
+            .collect();
+        for road in roads {
+            for ticket in self.tickets.remove(&road).unwrap_or_default() {
+                self.dispatch(ticket);
+            }
+        }
+    }
+
+    /// Connected dispatchers of every road that has any.
+    pub fn dispatchers(&self) -> BTreeMap<u16, Vec<DispatcherId>> {
+        self.dispatchers
+            .iter()
+            .map(|(road, dispatchers)| (*road, dispatchers.iter().map(|(id, _)| *id).collect()))
+            .collect()
+    }
+
+    /// Tickets waiting for a dispatcher, by road.
+    pub fn pending(&self) -> BTreeMap<u16, &[Ticket]> {
+        self.tickets
+            .iter()
+            .filter(|(_, tickets)| !tickets.is_empty())
+            .map(|(road, tickets)| (*road, tickets.as_slice()))
+            .collect()
+    }
+
+    /// Forgets the ticketed days and issued tickets from before `cutoff` of
+    /// every plate not in `seen`, so plates that stopped passing cameras don't
+    /// stay around forever.
+    pub fn forget_before(&mut self, cutoff: u32, seen: &HashSet<String>) {
+        forget_days(&mut self.days, cutoff, |plate| seen.contains(plate));
+        self.issued.retain(|plate, tickets| {
+            if !seen.contains(plate) {
+                tickets.retain(|ticket| ticket.timestamp2 >= cutoff);
+            }
+            !tickets.is_empty()
+        });
+    }
+
+    pub fn issued(&self, plate: &str) -> &[Ticket] {
+        self.issued
+            .get(plate)
+            .map_or(&[], |tickets| tickets.as_slice())
+    }
+}
+
+pub type CameraId = u64;
+
+#[derive(Debug)]
+struct Road {
+    limit: u16,
+    // Connected cameras and their mile
+    cameras: Vec<(CameraId, u16)>,
+}
+
+/// Speed limit of every road, as announced by the first camera on it, and the
+/// cameras currently connected on each road.
+#[derive(Debug, Default)]
+pub struct Roads {
+    next_camera: AtomicU64,
+    roads: Mutex<HashMap<u16, Road>>,
+}
+
+impl Roads {
+    /// Registers a camera, or returns the limit already on record for its road
+    /// if the camera disagrees with it.
+    pub fn add_camera(&self, road: u16, mile: u16, limit: u16) -> Result<CameraId, u16> {
+        let mut roads = self.roads.lock().unwrap();
+        let entry = roads.entry(road).or_insert(Road {
+            limit,
+            cameras: vec![],
+        });
+        if entry.limit != limit {
+            return Err(entry.limit);
+        }
+        let id = self.next_camera.fetch_add(1, Ordering::Relaxed);
+        entry.cameras.push((id, mile));
+        Ok(id)
+    }
+
+    /// Forgets a disconnected camera. The road keeps its limit.
+    pub fn remove_camera(&self, road: u16, id: CameraId) {
+        if let Some(entry) = self.roads.lock().unwrap().get_mut(&road) {
+            entry.cameras.retain(|(other, _)| *other != id);
+        }
+    }
+
+    /// Records the limit of a road another node has a camera on, unless one
+    /// is already known.
+    pub fn learn_limit(&self, road: u16, limit: u16) {
+        self.roads.lock().unwrap().entry(road).or_insert(Road {
+            limit,
+            cameras: vec![],
+        });
+    }
+
+    pub fn limit(&self, road: u16) -> Option<u16> {
+        self.roads
+            .lock()
+            .unwrap()
+            .get(&road)
+            .map(|entry| entry.limit)
+    }
+
+    /// Every known road with its limit and the miles of its connected cameras.
+    pub fn cameras(&self) -> BTreeMap<u16, (u16, Vec<u16>)> {
+        self.roads
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(road, entry)| {
+                let miles = entry.cameras.iter().map(|(_, mile)| *mile).collect();
+                (*road, (entry.limit, miles))
+            })
+            .collect()
+    }
+}
+
+/// Pairs of readings of one plate waiting out the reorder window.
+#[derive(Debug, Default)]
+pub struct Settling {
+    // When each pair is due, with its road and both readings
+    pub pairs: Vec<(Instant, u16, Position, Position)>,
+    // Whether a task is checking this plate's pairs, which no other may do
+    // meanwhile or the pairs could be checked out of order
+    pub checking: bool,
+}
+
+impl Settling {
+    /// Takes the pairs due by `now` in timestamp order, up to the first pair
+    /// that isn't due yet. A reading that arrived late may have formed that
+    /// pair, and it must still be checked before the pairs after it.
+    pub fn take_ready(&mut self, now: Instant) -> Vec<(u16, Position, Position)> {
+        self.pairs
+            .sort_by_key(|&(_, _, prev, next)| (next.timestamp, prev.timestamp));
+        let ready = self.pairs.iter().take_while(|pair| pair.0 <= now).count();
+        self.pairs
+            .drain(..ready)
+            .map(|(_, road, prev, next)| (road, prev, next))
+            .collect()
+    }
+}
+
+/// Everything shared between connections.
 #[derive(Debug, Default)]
 pub struct State {
-    pub cameras: HashMap<(String, u16), Position>,
-    pub clients: HashMap<u16, Sender<ServerToClientMessage>>,
-}
\ No newline at end of file
+    pub positions: Positions,
+    pub roads: Roads,
+    // Plate -> Pairs not checked for speeding yet
+    pub settling: Mutex<HashMap<String, Settling>>,
+    pub invalid_plates: InvalidPlates,
+    // Only ever held briefly and never across an await, so a slow dispatcher
+    // can't hold up cameras
+    pub ticket_state: Mutex<TicketState>,
+    pub store: Option<Store>,
+    pub audit: Option<AuditLog>,
+    pub backend: Box<dyn Backend>,
+    pub config: Config,
+}
+
+impl State {
+    /// Rebuilds the state recorded in `snapshot`.
+    pub fn restore(
+        snapshot: Snapshot,
+        store: Option<Store>,
+        audit: Option<AuditLog>,
+        backend: Box<dyn Backend>,
+        config: Config,
+    ) -> Self {
+        let positions = Positions::new(config.retention);
+        for observation in snapshot.observations {
+            positions.insert(
+                &observation.plate,
+                observation.road,
+                Position {
+                    timestamp: observation.timestamp,
+                    mile: observation.mile,
+                },
+            );
+        }
+
+        let mut ticket_state = TicketState {
+            days: snapshot.days,
+            max_pending: Some(config.max_pending_per_road),
+            ..Default::default()
+        };
+        // No dispatcher is connected yet, so these wait in the road's queue.
+        for ticket in snapshot.tickets {
+            ticket_state.dispatch(ticket);
+        }
+
+        State {
+            positions,
+            roads: Roads::default(),
+            settling: Default::default(),
+            invalid_plates: InvalidPlates::default(),
+            ticket_state: Mutex::new(ticket_state),
+            store,
+            audit,
+            backend,
+            config,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::testing::ticket;
+
+    #[test]
+    fn day_span_of_readings() {
+        assert_eq!(day_span(0, 45), (0, 0));
+        assert_eq!(day_span(86399, 86400), (0, 1));
+        assert_eq!(day_span(u32::MAX, 0), (0, u32::MAX / DAY));
+    }
+
+    #[test]
+    fn ticketed_days_merge_and_overlap() {
+        let mut days = TicketedDays::default();
+        days.insert(5, 6);
+        days.insert(10, 10);
+        assert!(!days.overlaps(7, 9));
+        assert!(days.overlaps(6, 9));
+        assert!(days.overlaps(0, 100));
+        assert!(!days.overlaps(11, u32::MAX));
+
+        days.insert(7, 9);
+        assert_eq!(days.ranges().collect::<Vec<_>>(), vec![(5, 10)]);
+        days.insert(0, 2);
+        days.insert(1, 20);
+        assert_eq!(days.ranges().collect::<Vec<_>>(), vec![(0, 20)]);
+    }
+
+    #[test]
+    fn claim_days_once() {
+        let mut state = TicketState::default();
+        assert!(state.claim_days("UN1X", 0, 1));
+        assert!(!state.claim_days("UN1X", 1, 2));
+        assert!(state.claim_days("UN1X", 2, 2));
+        assert!(state.claim_days("RE05BKG", 1, 1));
+    }
+
+    #[test]
+    fn forget_tickets_of_unseen_plates() {
+        let mut state = TicketState::default();
+        for plate in ["UN1X", "RE05BKG"] {
+            state.claim_days(plate, 0, 0);
+            state.claim_days(plate, 2, 2);
+            state.issued_elsewhere(Ticket {
+                plate: plate.to_string(),
+                ..ticket(66)
+            });
+        }
+
+        let seen = HashSet::from(["RE05BKG".to_string()]);
+        state.forget_before(2 * DAY, &seen);
+        assert_eq!(state.days["UN1X"].ranges().collect::<Vec<_>>(), [(2, 2)]);
+        assert_eq!(state.issued("UN1X"), []);
+        assert_eq!(state.days["RE05BKG"].ranges().count(), 2);
+        assert_eq!(state.issued("RE05BKG").len(), 1);
+
+        state.forget_before(3 * DAY, &seen);
+        assert!(!state.days.contains_key("UN1X"));
+        assert!(state.claim_days("UN1X", 0, 0));
+    }
+
+    #[test]
+    fn conflicting_road_limit() {
+        let roads = Roads::default();
+        let first = roads.add_camera(66, 8, 60).unwrap();
+        let second = roads.add_camera(66, 9, 60).unwrap();
+        assert_eq!(roads.add_camera(66, 10, 70), Err(60));
+        roads.add_camera(67, 8, 70).unwrap();
+        assert_eq!(roads.limit(66), Some(60));
+        assert_eq!(roads.limit(68), None);
+
+        roads.remove_camera(66, first);
+        roads.remove_camera(66, second);
+        assert_eq!(roads.add_camera(66, 10, 70), Err(60));
+        assert_eq!(roads.cameras()[&66], (60, vec![]));
+        assert_eq!(roads.cameras()[&67], (70, vec![8]));
+    }
+
+    #[test]
+    fn dispatch_skips_disconnected_dispatcher() {
+        let mut state = TicketState::default();
+        let (first, first_receiver) = mpsc::channel(8);
+        let (second, mut second_receiver) = mpsc::channel(8);
+        state.add_dispatcher(&[66], first);
+        state.add_dispatcher(&[66, 67], second);
+
+        drop(first_receiver);
+        state.dispatch(ticket(66));
+        assert_eq!(second_receiver.try_recv().unwrap().road, 66);
+    }
+
+    #[test]
+    fn tickets_wait_for_next_dispatcher() {
+        let mut state = TicketState::default();
+        let (sender, mut receiver) = mpsc::channel(8);
+        let id = state.add_dispatcher(&[66], sender);
+        state.remove_dispatcher(id);
+
+        state.dispatch(ticket(66));
+        assert!(receiver.try_recv().is_err());
+
+        let (sender, mut receiver) = mpsc::channel(8);
+        state.add_dispatcher(&[66], sender);
+        assert_eq!(receiver.try_recv().unwrap().road, 66);
+        assert!(state.tickets.is_empty());
+    }
+
+    #[test]
+    fn full_dispatcher_waits_for_refill() {
+        let mut state = TicketState {
+            max_pending: Some(2),
+            ..Default::default()
+        };
+        let (sender, mut receiver) = mpsc::channel(1);
+        let id = state.add_dispatcher(&[66], sender);
+        for timestamp in 0..4 {
+            state.dispatch(Ticket {
+                timestamp1: timestamp,
+                ..ticket(66)
+            });
+        }
+        // One ticket is queued with the dispatcher, the next two wait with the
+        // road and the one before them was dropped.
+        assert_eq!(state.pending()[&66].len(), 2);
+        assert_eq!(state.dropped, 1);
+
+        assert_eq!(receiver.try_recv().unwrap().timestamp1, 0);
+        state.refill(id);
+        assert_eq!(receiver.try_recv().unwrap().timestamp1, 2);
+        assert_eq!(state.pending()[&66].len(), 1);
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::jsonl::{self, Appender, Sink};
+use crate::positions::Retention;
+use crate::state::{self, Ticket, TicketedDays, DAY};
+
+const SNAPSHOT_FILE: &str = "snapshot.json";
+const LOG_FILE: &str = "log.jsonl";
+
+// Compact the log into a fresh snapshot after this many appended records, or
+// after as many as the last snapshot held if that is more, so compaction
+// costs a constant amount per record however large the snapshot gets.
+const SNAPSHOT_EVERY: u64 = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Observation {
+    pub plate: String,
+    pub road: u16,
+    pub mile: u16,
+    pub timestamp: u32,
+}
+
+/// A single line of the append-only log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+    Observation(Observation),
+    // A ticket was issued and is waiting to be written to a dispatcher
+    Ticket(Ticket),
+    // A ticket was written to a dispatcher
+    Delivered(Ticket),
+    TicketedDays {
+        plate: String,
+        first_day: u32,
+        last_day: u32,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    seq: u64,
+    #[serde(flatten)]
+    record: Record,
+}
+
+/// Everything the daemon needs to pick up where it left off.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    // Sequence number of the last record folded into this snapshot
+    seq: u64,
+    pub observations: Vec<Observation>,
+    // Issued tickets that no dispatcher has received yet
+    pub tickets: Vec<Ticket>,
+    // Plate -> Days with tickets
+    pub days: HashMap<String, TicketedDays>,
+}
+
+impl Snapshot {
+    /// Drops the observations that [`crate::positions::Positions`] would no
+    /// longer keep under `retention`, and the ticketed days of plates left
+    /// without any, so the snapshot doesn't grow with the whole history.
+    fn prune(&mut self, retention: &Retention) {
+        let Some(latest) = self.observations.iter().map(|o| o.timestamp).max() else {
+            return;
+        };
+        if let Some(window) = retention.window {
+            let cutoff = latest.saturating_sub(window);
+            self.observations.retain(|o| o.timestamp >= cutoff);
+        }
+        if let Some(days) = retention.idle_days {
+            let cutoff = latest.saturating_sub(days.saturating_mul(DAY));
+            let keep: Vec<bool> = {
+                // (Plate,Road) -> Latest timestamp
+                let mut seen: HashMap<(&str, u16), u32> = HashMap::new();
+                for o in &self.observations {
+                    let latest = seen.entry((&o.plate, o.road)).or_default();
+                    *latest = (*latest).max(o.timestamp);
+                }
+                self.observations
+                    .iter()
+                    .map(|o| seen[&(o.plate.as_str(), o.road)] >= cutoff)
+                    .collect()
+            };
+            let mut keep = keep.into_iter();
+            self.observations.retain(|_| keep.next().unwrap());
+        }
+        if let Some(max) = retention.max_readings {
+            // Observations are in the order they were recorded.
+            let over = self.observations.len().saturating_sub(max);
+            self.observations.drain(..over);
+        }
+        if let Some(cutoff) = retention.forget_tickets_before(latest) {
+            let seen: HashSet<&str> = self.observations.iter().map(|o| o.plate.as_str()).collect();
+            state::forget_days(&mut self.days, cutoff, |plate| seen.contains(plate));
+        }
+    }
+
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Observation(observation) => self.observations.push(observation),
+            Record::Ticket(ticket) => self.tickets.push(ticket),
+            Record::Delivered(ticket) => {
+                if let Some(i) = self.tickets.iter().position(|t| *t == ticket) {
+                    self.tickets.remove(i);
+                }
+            }
+            Record::TicketedDays {
+                plate,
+                first_day,
+                last_day,
+            } => {
+                self.days
+                    .entry(plate)
+                    .or_default()
+                    .insert(first_day, last_day);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Log {
+    dir: PathBuf,
+    writer: BufWriter<File>,
+    retention: Retention,
+    seq: u64,
+    since_snapshot: u64,
+    // Observations and tickets in the last snapshot
+    snapshot_len: u64,
+}
+
+/// On-disk store made of a snapshot plus an append-only log of everything
+/// recorded since.
+///
+/// Every log entry carries a sequence number, so entries that already made it
+/// into the snapshot are skipped if the daemon stopped halfway through a
+/// compaction.
+///
+/// Records are written and compacted on a thread of their own, never on the
+/// connection that recorded them.
+#[derive(Debug, Clone)]
+pub struct Store {
+    log: Appender<Record>,
+}
+
+fn read_state(dir: &Path, retention: &Retention) -> io::Result<Snapshot> {
+    let mut snapshot: Snapshot = match fs::read(dir.join(SNAPSHOT_FILE)) {
+        Ok(bytes) => serde_json::from_slice(&bytes)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Snapshot::default(),
+        Err(e) => return Err(e),
+    };
+
+    let entries = match jsonl::read::<Entry>(dir.join(LOG_FILE)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            snapshot.prune(retention);
+            return Ok(snapshot);
+        }
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let (_, entry) = entry?;
+        if entry.seq > snapshot.seq {
+            snapshot.seq = entry.seq;
+            snapshot.apply(entry.record);
+        }
+    }
+    snapshot.prune(retention);
+    Ok(snapshot)
+}
+
+impl Log {
+    /// Folds the log into a new snapshot and starts an empty log.
+    fn compact(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        let snapshot = read_state(&self.dir, &self.retention)?;
+
+        let tmp = self.dir.join(format!("{SNAPSHOT_FILE}.tmp"));
+        let mut file = File::create(&tmp)?;
+        serde_json::to_writer(&mut file, &snapshot)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(SNAPSHOT_FILE))?;
+
+        self.writer = BufWriter::new(File::create(self.dir.join(LOG_FILE))?);
+        self.since_snapshot = 0;
+        self.snapshot_len = (snapshot.observations.len() + snapshot.tickets.len()) as u64;
+        Ok(())
+    }
+}
+
+impl Sink<Record> for Log {
+    fn append(&mut self, record: Record) -> io::Result<()> {
+        self.seq += 1;
+        let entry = Entry {
+            seq: self.seq,
+            record,
+        };
+        jsonl::write_line(&mut self.writer, &entry)?;
+
+        self.since_snapshot += 1;
+        if self.since_snapshot >= SNAPSHOT_EVERY.max(self.snapshot_len) {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Store {
+    /// Opens the store in `dir`, creating it if needed, and returns the state
+    /// recorded by previous runs. Observations past `retention` are dropped
+    /// from it.
+    pub fn open(dir: impl AsRef<Path>, retention: Retention) -> io::Result<(Store, Snapshot)> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let snapshot = read_state(&dir, &retention)?;
+
+        let writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(LOG_FILE))?;
+        let mut log = Log {
+            dir,
+            writer: BufWriter::new(writer),
+            retention,
+            seq: snapshot.seq,
+            since_snapshot: 0,
+            snapshot_len: 0,
+        };
+        log.compact()?;
+
+        let store = Store {
+            log: Appender::spawn("store", log),
+        };
+        Ok((store, snapshot))
+    }
+
+    /// Queues `record` to be written.
+    pub fn append(&self, record: Record) {
+        self.log.append(record);
+    }
+
+    /// Waits until every record appended so far is written.
+    pub fn sync(&self) {
+        self.log.sync();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{temp_path, ticket};
+
+    #[test]
+    fn replay_after_restart() {
+        let dir = temp_path("replay");
+        {
+            let (store, snapshot) = Store::open(&dir, Retention::default()).unwrap();
+            assert!(snapshot.observations.is_empty());
+            store.append(Record::Observation(Observation {
+                plate: "UN1X".to_string(),
+                road: 66,
+                mile: 8,
+                timestamp: 0,
+            }));
+            store.append(Record::Ticket(ticket(66)));
+            store.append(Record::TicketedDays {
+                plate: "UN1X".to_string(),
+                first_day: 0,
+                last_day: 0,
+            });
+            store.append(Record::Ticket(Ticket {
+                road: 67,
+                ..ticket(66)
+            }));
+            store.append(Record::Delivered(ticket(66)));
+        }
+
+        let (_, snapshot) = Store::open(&dir, Retention::default()).unwrap();
+        assert_eq!(snapshot.observations.len(), 1);
+        assert_eq!(snapshot.tickets.len(), 1);
+        assert_eq!(snapshot.tickets[0].road, 67);
+        assert!(snapshot.days["UN1X"].overlaps(0, 0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skip_records_already_in_snapshot() {
+        let dir = temp_path("skip");
+        let (store, _) = Store::open(&dir, Retention::default()).unwrap();
+        store.append(Record::Ticket(ticket(66)));
+        drop(store);
+
+        // Simulate a crash between writing the snapshot and truncating the log.
+        let log = fs::read(dir.join(LOG_FILE)).unwrap();
+        let (_, snapshot) = Store::open(&dir, Retention::default()).unwrap();
+        assert_eq!(snapshot.tickets.len(), 1);
+        fs::write(dir.join(LOG_FILE), log).unwrap();
+
+        let (_, snapshot) = Store::open(&dir, Retention::default()).unwrap();
+        assert_eq!(snapshot.tickets.len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keep_state_within_retention() {
+        let dir = temp_path("retention");
+        let (store, _) = Store::open(&dir, Retention::default()).unwrap();
+        for (plate, timestamp) in [("UN1X", 0), ("RE05BKG", 10), ("UN1X", 2 * DAY)] {
+            store.append(Record::Observation(Observation {
+                plate: plate.to_string(),
+                road: 66,
+                mile: 8,
+                timestamp,
+            }));
+            store.append(Record::TicketedDays {
+                plate: plate.to_string(),
+                first_day: 0,
+                last_day: 0,
+            });
+        }
+        store.sync();
+
+        let retention = Retention {
+            idle_days: Some(1),
+            ..Retention::default()
+        };
+        let (_, snapshot) = Store::open(&dir, retention).unwrap();
+        let kept: Vec<_> = snapshot.observations.iter().map(|o| o.timestamp).collect();
+        assert_eq!(kept, [0, 2 * DAY]);
+        // RE05BKG has no readings left, so its ticketed days go with them.
+        assert_eq!(snapshot.days.keys().collect::<Vec<_>>(), ["UN1X"]);
+
+        let retention = Retention {
+            window: Some(60),
+            ..Retention::default()
+        };
+        let (_, snapshot) = Store::open(&dir, retention).unwrap();
+        assert_eq!(snapshot.observations.len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
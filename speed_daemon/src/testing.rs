@@ -0,0 +1,28 @@
+//! Fixtures shared by the unit tests.
+
+use std::fs;
+use std::path::PathBuf;
+
+use crate::state::Ticket;
+
+/// An 80 mph ticket for UN1X on `road`, from mile 8 to mile 9 on day 0.
+pub fn ticket(road: u16) -> Ticket {
+    Ticket {
+        plate: "UN1X".to_string(),
+        road,
+        mile1: 8,
+        timestamp1: 0,
+        mile2: 9,
+        timestamp2: 45,
+        speed: 8000,
+    }
+}
+
+/// A path in the temp directory that only this test process uses, with
+/// whatever an earlier run left there removed.
+pub fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("speed_daemon-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+    let _ = fs::remove_file(&path);
+    path
+}